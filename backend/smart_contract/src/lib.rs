@@ -1,14 +1,22 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    clock::Clock,
     entrypoint,
-    entrypoint::ProgramResult,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    log::sol_log_data,
     msg,
-    pubkey::Pubkey,
+    program::{invoke, invoke_signed, set_return_data},
+    pubkey::{Pubkey, MAX_SEED_LEN},
     program_error::ProgramError,
+    rent::Rent,
+    system_program,
+    sysvar::Sysvar,
 };
+use solana_system_interface::instruction::{allocate, assign, create_account, transfer};
 use std::collections::HashMap;
+use borsh::{BorshDeserialize, BorshSerialize};
 use serde::{Serialize, Deserialize};
-use serde_json;
+use thiserror::Error;
 
 // Declare the program's entry point
 entrypoint!(process_instruction);
@@ -16,70 +24,2580 @@ entrypoint!(process_instruction);
 // Define the program's ID 
 solana_program::declare_id!("3oYm2ArhEFxH42uBZpsEqBzqfrWH4xquop4oNStTJ6M6");
 
+// Seed prefix for the per-owner CID account PDA
+pub const CID_SEED: &[u8] = b"cid";
+
+// Seed prefix for organization authority PDAs, derived under the org's
+// own authority program
+pub const ORG_SEED: &[u8] = b"org";
+
+// Bounds on the key-value metadata attached to a single store
+pub const MAX_METADATA_PAIRS: usize = 8;
+pub const MAX_METADATA_BYTES: usize = 512;
+
+// Layout version `CidStorage::save` writes
+pub const STORAGE_VERSION: u32 = 4;
+
+// Shortest time-lock a recovery key can be given, in seconds, so the owner
+// always has a window to notice a recovery request and cancel it
+pub const MIN_RECOVERY_DELAY: i64 = 3_600;
+
+// Longest CID string an account will store, and how many it will keep.
+// MAX_CIDS is what fits CidAccount::SPACE into a single 10 KiB allocation.
+pub const MAX_CID_LEN: usize = 128;
+pub const MAX_CIDS: usize = 48;
+// Largest page history_page will return, whatever limit is asked for
+pub const MAX_HISTORY_PAGE: usize = 20;
+// Longest label that can be attached to a stored CID
+pub const MAX_LABEL_LEN: usize = 32;
+
+// CIDv0 is always a 46-character base58btc sha2-256 multihash ("Qm...")
+const CIDV0_LEN: usize = 46;
+// Shortest base32 CIDv1 we accept: a sha2-256 multihash, which is the common case
+const CIDV1_MIN_LEN: usize = 59;
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+// Program-specific errors, surfaced as ProgramError::Custom
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum CidError {
+    #[error("No recovery has been requested for this account")]
+    NoPendingRecovery,
+    #[error("Recovery time-lock has not elapsed yet")]
+    RecoveryLocked,
+    #[error("Metadata exceeds the allowed number of pairs or total size")]
+    MetadataTooLarge,
+    #[error("Previous write has not reached the required slot gap")]
+    WriteTooSoon,
+    #[error("Account is sealed and no longer accepts CIDs")]
+    AccountSealed,
+    #[error("Signer is not authorized for this account")]
+    Unauthorized,
+    #[error("CID not found in the account history")]
+    CidNotFound,
+    #[error("CID is not a well-formed CIDv0 or base32 CIDv1")]
+    InvalidCid,
+    #[error("CID is longer than MAX_CID_LEN")]
+    CidTooLong,
+    #[error("Account already holds MAX_CIDS CIDs")]
+    TooManyCids,
+    #[error("CID is already stored on this account")]
+    DuplicateCid,
+    #[error("Index is past the end of the CID history")]
+    IndexOutOfRange,
+    #[error("Label is longer than MAX_LABEL_LEN")]
+    LabelTooLong,
+}
+
+impl From<CidError> for ProgramError {
+    fn from(e: CidError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+// Instructions understood by the program. Borsh encodes these as a one-byte
+// discriminator (the variant index) followed by the variant's fields.
+//
+// Accounts, in order, unless a variant lists its own:
+//   0. [writable] CID account, the [b"cid", seed_owner] PDA (see cid_account_address)
+//   1. [signer] owner, for every instruction except the Get* reads; the recovery key
+//      for RequestRecovery and Recover. Writable for Initialize, which it pays
+//      the rent for, and CloseAccount, which refunds it.
+//   2. system program, for Initialize and InitializeWithRecovery only
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum CidInstruction {
+    Initialize { owner: Pubkey },
+    StoreCid { cid: String },
+    RemoveCid { cid: String },
+    CloseAccount,
+    StoreCids { cids: Vec<String> },
+    TransferOwnership { new_owner: Pubkey },
+    // Read-only: returns the CID at `index` as the instruction's return data
+    GetCidAt { index: u64 },
+    StoreCidLabeled { cid: String, label: String },
+    ClearHistory,
+    StoreCidWithSize { cid: String, claimed_size: u64 },
+    SetRecoveryKey { recovery_key: Option<Pubkey>, delay: i64 },
+    // RequestRecovery and Recover are signed by the recovery key, not the owner
+    RequestRecovery,
+    Recover { new_owner: Pubkey },
+    StoreCidWithKv { cid: String, kv: Vec<(String, String)> },
+    // Create the bucket owned by the [b"org", namespace] PDA of
+    // `authority_program`. Accounts: 0. the org PDA's CID account,
+    // 1. [signer] org PDA, which the authority program signs for with
+    // invoke_signed, 2. [signer, writable] payer, 3. system program
+    InitializeOrg { namespace: String, bump: u8, authority_program: Pubkey },
+    // Store on an org bucket, signed by the org PDA as for InitializeOrg
+    StoreCidAsOrg { namespace: String, bump: u8, cid: String },
+    SetMinSlotGap { gap: u64 },
+    StoreFinal { cid: String },
+    StoreCidTemporary { cid: String, expiry_slot: u64 },
+    // StoreCid with an opt-in for re-pinning a CID the account already holds
+    StoreCidWithFlags { cid: String, allow_duplicates: bool },
+    // Initialize with a recovery key already set, as SetRecoveryKey would
+    InitializeWithRecovery { owner: Pubkey, recovery_key: Pubkey, delay: i64 },
+    // Read-only: returns the CID readers should see right now as the return
+    // data, which differs from `latest_cid` once a temporary store expires
+    GetEffectiveCid,
+}
+
+// Events for off-chain indexers, written with sol_log_data as the Borsh
+// encoding of the variant (one-byte discriminator followed by the fields)
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum CidEvent {
+    AccountInitialized { account: Pubkey, owner: Pubkey },
+    CidStored { account: Pubkey, owner: Pubkey, cid: String, count: u64 },
+    OwnershipTransferred { account: Pubkey, old_owner: Pubkey, new_owner: Pubkey },
+    HistoryCleared { account: Pubkey, owner: Pubkey },
+    // Follows the CidStored of a store that carried key-value metadata
+    MetadataAttached { account: Pubkey, cid: String, kv: Vec<(String, String)> },
+    // The recovery key started its time-lock; Recover works from `unlocks_at`
+    RecoveryRequested { account: Pubkey, recovery_key: Pubkey, unlocks_at: i64 },
+}
+
+impl CidEvent {
+    pub fn emit(&self) -> Result<(), ProgramError> {
+        let data = borsh::to_vec(self).map_err(|_| ProgramError::BorshIoError("CidEvent".to_string()))?;
+        sol_log_data(&[&data]);
+        Ok(())
+    }
+}
+
 // Account structure to store CID data
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
 pub struct CidAccount {
     pub owner: Pubkey,
+    // Bump of the [b"cid", seed_owner] PDA this account was initialized at
+    #[serde(default)]
+    pub bump: u8,
+    // Owner the account address was derived from. It stays put when
+    // ownership moves, so the address can always be re-derived from it.
+    #[serde(default)]
+    pub seed_owner: Pubkey,
     pub cid_count: u64,
+    // Convenience pointer to the last entry of `cids`
     pub latest_cid: String,
+    // Every CID stored on this account, oldest first
+    #[serde(default)]
+    pub cids: Vec<String>,
+    // Unix timestamp at which each entry of `cids` was stored (0 if unknown)
+    #[serde(default)]
+    pub timestamps: Vec<i64>,
+    // Human-readable label for each entry of `cids` (empty when unlabeled)
+    #[serde(default)]
+    pub labels: Vec<String>,
+    // Unix timestamps of initialization and of the most recent store
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default)]
+    pub last_updated: i64,
+    // Content length the uploader committed to for `latest_cid`, if any.
+    // Only the latest store's claim is kept; any later store replaces it.
+    #[serde(default)]
+    pub claimed_size: Option<u64>,
+    // Free-form key-value pairs attached to `latest_cid`. Like claimed_size
+    // this is latest-only; the MetadataAttached event keeps the full record.
+    #[serde(default)]
+    pub metadata: Vec<(String, String)>,
+    // Key allowed to reclaim the account if the owner loses theirs
+    #[serde(default)]
+    pub recovery_key: Option<Pubkey>,
+    // Seconds a recovery request must wait before ownership can move
+    #[serde(default)]
+    pub recovery_delay: i64,
+    // Unix timestamp at which the pending recovery was requested
+    #[serde(default)]
+    pub recovery_requested_at: Option<i64>,
+    // Organization namespace when `owner` is an org PDA rather than a wallet,
+    // and the program that PDA is derived under and decides who may sign for it
+    #[serde(default)]
+    pub org_namespace: Option<String>,
+    #[serde(default)]
+    pub org_program: Option<Pubkey>,
+    // Minimum number of slots between consecutive stores (0 disables the gate)
+    #[serde(default)]
+    pub min_slot_gap: u64,
+    // Slot of the most recent store
+    #[serde(default)]
+    pub last_write_slot: Option<u64>,
+    // Set once a final CID has been stored; from then on the history can no
+    // longer change and the account cannot be closed
+    #[serde(default)]
+    pub sealed: bool,
+    // CID that was effective before the latest store
+    #[serde(default)]
+    pub prev_cid: String,
+    // First slot at which a temporary `latest_cid` reads as `prev_cid`
+    #[serde(default)]
+    pub expiry_slot: Option<u64>,
+}
+
+impl CidAccount {
+    // Leading 8 bytes of raw account data, first 8 bytes of
+    // sha256("account:CidAccount") as Anchor lays it out
+    pub const DISCRIMINATOR: [u8; 8] = [99, 254, 186, 140, 197, 155, 94, 129];
+
+    // Account data length that holds any CidAccount within the limits above:
+    // the discriminator plus the largest Borsh encoding of every field. Every
+    // account is created at this size (about 0.067 SOL of rent, refunded on
+    // close), so a full history never needs a realloc.
+    pub const SPACE: usize = 8
+        + 32 + 1 + 32 + 8                                   // owner, bump, seed_owner, cid_count
+        + 4 + MAX_CID_LEN                                   // latest_cid
+        + 4 + MAX_CIDS * (4 + MAX_CID_LEN)                  // cids
+        + 4 + MAX_CIDS * 8                                  // timestamps
+        + 4 + MAX_CIDS * (4 + MAX_LABEL_LEN)                // labels
+        + 8 + 8                                             // created_at, last_updated
+        + 1 + 8                                             // claimed_size
+        + 4 + MAX_METADATA_PAIRS * (4 + 4) + MAX_METADATA_BYTES // metadata
+        + 1 + 32 + 8 + 1 + 8                                // recovery_key, recovery_delay, recovery_requested_at
+        + 1 + 4 + MAX_SEED_LEN + 1 + 32                     // org_namespace, org_program
+        + 8 + 1 + 8 + 1                                     // min_slot_gap, last_write_slot, sealed
+        + 4 + MAX_CID_LEN                                   // prev_cid
+        + 1 + 8;                                            // expiry_slot
+
+    // Decode raw account data: the discriminator followed by the Borsh fields
+    pub fn try_from_account_data(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.len() < 8 {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        let (discriminator, mut body) = data.split_at(8);
+        if discriminator != Self::DISCRIMINATOR {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let cid_account: Self = BorshDeserialize::deserialize(&mut body)
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if !cid_account.is_aligned() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(cid_account)
+    }
+
+    // Encode as raw account data, the inverse of `try_from_account_data`
+    pub fn to_account_data(&self) -> Vec<u8> {
+        let mut data = Self::DISCRIMINATOR.to_vec();
+        BorshSerialize::serialize(self, &mut data).expect("writing to a Vec cannot fail");
+        data
+    }
+
+    // Write `to_account_data` into a fixed-size account buffer, zeroing
+    // whatever is left over from a longer previous encoding
+    pub fn pack_into(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        let data = self.to_account_data();
+        if dst.len() < data.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        let (head, tail) = dst.split_at_mut(data.len());
+        head.copy_from_slice(&data);
+        tail.fill(0);
+        Ok(())
+    }
+
+    fn new(owner: Pubkey, clock: &Clock) -> Self {
+        Self {
+            owner,
+            bump: 0,
+            seed_owner: owner,
+            cid_count: 0,
+            latest_cid: String::new(),
+            cids: Vec::new(),
+            timestamps: Vec::new(),
+            labels: Vec::new(),
+            created_at: clock.unix_timestamp,
+            last_updated: clock.unix_timestamp,
+            claimed_size: None,
+            metadata: Vec::new(),
+            recovery_key: None,
+            recovery_delay: 0,
+            recovery_requested_at: None,
+            org_namespace: None,
+            org_program: None,
+            min_slot_gap: 0,
+            last_write_slot: None,
+            sealed: false,
+            prev_cid: String::new(),
+            expiry_slot: None,
+        }
+    }
+
+    // Whether `timestamps` and `labels` have one entry per CID, which the
+    // history operations index by
+    pub fn is_aligned(&self) -> bool {
+        self.timestamps.len() == self.cids.len() && self.labels.len() == self.cids.len()
+    }
+
+    // A bucket owned by the org PDA of `namespace` under `authority_program`
+    fn new_org(namespace: &str, bump: u8, authority_program: Pubkey, clock: &Clock) -> Result<Self, ProgramError> {
+        let mut cid_account = Self::new(org_authority(namespace, bump, &authority_program)?, clock);
+        cid_account.org_namespace = Some(namespace.to_string());
+        cid_account.org_program = Some(authority_program);
+        Ok(cid_account)
+    }
+
+    pub fn get_cid_at(&self, index: usize) -> Option<&String> {
+        self.cids.get(index)
+    }
+
+    // CID at `index` in the history, oldest first
+    pub fn get_cid_by_index(&self, index: u64) -> Result<&String, ProgramError> {
+        usize::try_from(index).ok()
+            .and_then(|index| self.get_cid_at(index))
+            .ok_or_else(|| CidError::IndexOutOfRange.into())
+    }
+
+    // Up to `limit` (clamped to MAX_HISTORY_PAGE) history entries starting at
+    // `offset`, plus the total history length. An offset past the end yields
+    // an empty page.
+    pub fn history_page(&self, offset: usize, limit: usize) -> (Vec<String>, usize) {
+        let page = self.cids.iter()
+            .skip(offset)
+            .take(limit.min(MAX_HISTORY_PAGE))
+            .cloned()
+            .collect();
+
+        (page, self.cids.len())
+    }
+
+    // Most recent CID stored under `label`
+    pub fn find_by_label(&self, label: &str) -> Option<&String> {
+        self.labels.iter().rposition(|l| !l.is_empty() && l == label)
+            .and_then(|index| self.cids.get(index))
+    }
+
+    // The CID readers should see: `latest_cid`, unless it was stored as
+    // temporary and its expiry slot has passed
+    pub fn effective_cid(&self, clock: &Clock) -> &str {
+        match self.expiry_slot {
+            Some(expiry_slot) if clock.slot >= expiry_slot => &self.prev_cid,
+            _ => &self.latest_cid,
+        }
+    }
+
+    // Compare the committed size against the length actually served by a gateway.
+    // Returns false when no size was committed for the latest CID.
+    pub fn verify_content_length(&self, actual_size: u64) -> bool {
+        self.claimed_size == Some(actual_size)
+    }
+
+    pub fn store_cid(&mut self, signer: &Pubkey, cid: String, clock: &Clock) -> Result<(), ProgramError> {
+        self.write_cids(signer, vec![cid], StoreExtras::default(), clock)
+    }
+
+    // Like store_cid, but for callers that intentionally re-pin a CID the
+    // account already holds
+    pub fn store_cid_allow_duplicate(&mut self, signer: &Pubkey, cid: String, clock: &Clock) -> Result<(), ProgramError> {
+        self.write_cids(signer, vec![cid], StoreExtras { allow_duplicates: true, ..StoreExtras::default() }, clock)
+    }
+
+    // Store a CID under a human-readable label, e.g. "avatar-v2"
+    pub fn store_cid_labeled(&mut self, signer: &Pubkey, cid: String, label: String, clock: &Clock) -> Result<(), ProgramError> {
+        if label.len() > MAX_LABEL_LEN {
+            return Err(CidError::LabelTooLong.into());
+        }
+
+        self.write_cids(signer, vec![cid], StoreExtras { label, ..StoreExtras::default() }, clock)
+    }
+
+    // Store a CID together with the content length the uploader claims for it,
+    // so a verifier can later detect truncated uploads
+    pub fn store_cid_with_size(&mut self, signer: &Pubkey, cid: String, claimed_size: u64, clock: &Clock) -> Result<(), ProgramError> {
+        if claimed_size == 0 {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        self.write_cids(signer, vec![cid], StoreExtras { claimed_size: Some(claimed_size), ..StoreExtras::default() }, clock)
+    }
+
+    // Store a CID tagged with a bounded set of key-value pairs,
+    // e.g. [("commit", "abc123"), ("pipeline", "nightly")]
+    pub fn store_cid_with_kv(&mut self, signer: &Pubkey, cid: String, kv: Vec<(String, String)>, clock: &Clock) -> Result<(), ProgramError> {
+        let total_bytes: usize = kv.iter().map(|(k, v)| k.len() + v.len()).sum();
+        if kv.len() > MAX_METADATA_PAIRS || total_bytes > MAX_METADATA_BYTES {
+            return Err(CidError::MetadataTooLarge.into());
+        }
+
+        self.write_cids(signer, vec![cid], StoreExtras { metadata: kv, ..StoreExtras::default() }, clock)
+    }
+
+    // Owner-only: set (or clear) the key that may reclaim this account, and the
+    // time-lock it has to wait out, at least MIN_RECOVERY_DELAY. Cancels any
+    // recovery already in flight.
+    pub fn set_recovery_key(&mut self, signer: &Pubkey, recovery_key: Option<Pubkey>, delay: i64) -> Result<(), ProgramError> {
+        if delay < 0 || (recovery_key.is_some() && delay < MIN_RECOVERY_DELAY) {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if self.owner != *signer {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        self.recovery_key = recovery_key;
+        self.recovery_delay = delay;
+        self.recovery_requested_at = None;
+
+        msg!("Recovery key updated");
+        Ok(())
+    }
+
+    // Start the recovery time-lock. Only the recovery key may do this.
+    pub fn request_recovery(&mut self, signer: &Pubkey, clock: &Clock) -> Result<(), ProgramError> {
+        if self.recovery_key != Some(*signer) {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        self.recovery_requested_at = Some(clock.unix_timestamp);
+
+        msg!("Recovery requested, unlocks in {}s", self.recovery_delay);
+        Ok(())
+    }
+
+    // Hand the account to `new_owner` once the requested recovery has waited
+    // out the time-lock
+    pub fn recover(&mut self, signer: &Pubkey, new_owner: Pubkey, clock: &Clock) -> Result<(), ProgramError> {
+        if self.recovery_key != Some(*signer) {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        let requested_at = self.recovery_requested_at
+            .ok_or(CidError::NoPendingRecovery)?;
+
+        if clock.unix_timestamp < requested_at.saturating_add(self.recovery_delay) {
+            return Err(CidError::RecoveryLocked.into());
+        }
+
+        self.owner = new_owner;
+        self.recovery_requested_at = None;
+
+        msg!("Account recovered to new owner: {}", new_owner);
+        Ok(())
+    }
+
+    // Owner-only: hand the account to `new_owner`; from then on only the new
+    // owner's signature is accepted for writes
+    pub fn transfer_ownership(&mut self, signer: &Pubkey, new_owner: Pubkey) -> Result<(), ProgramError> {
+        if self.owner != *signer {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        self.owner = new_owner;
+        // The previous owner's recovery setup does not carry over
+        self.recovery_key = None;
+        self.recovery_delay = 0;
+        self.recovery_requested_at = None;
+
+        msg!("Ownership transferred to: {}", new_owner);
+        Ok(())
+    }
+
+    // Owner-only: drop the most recent occurrence of `cid` from the history
+    pub fn remove_cid(&mut self, signer: &Pubkey, cid: &str) -> Result<(), ProgramError> {
+        if self.owner != *signer {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        if self.sealed {
+            return Err(CidError::AccountSealed.into());
+        }
+
+        let index = self.cids.iter().rposition(|stored| stored == cid)
+            .ok_or(CidError::CidNotFound)?;
+
+        self.cids.remove(index);
+        self.timestamps.remove(index);
+        self.labels.remove(index);
+        self.cid_count = self.cid_count.saturating_sub(1);
+        if index == self.cids.len() {
+            // The latest CID went away, and with it everything recorded about
+            // it alone: a pending temporary revert, its claimed size and metadata
+            self.expiry_slot = None;
+            self.claimed_size = None;
+            self.metadata.clear();
+        }
+        self.latest_cid = self.cids.last().cloned().unwrap_or_default();
+        if !self.cids.contains(&self.prev_cid) {
+            // Never fall back to a CID that is gone; the entry before the
+            // latest is the closest thing to what was effective before it
+            self.prev_cid = self.cids.iter().rev().nth(1).cloned().unwrap_or_default();
+        }
+
+        msg!("CID removed: {}", cid);
+        Ok(())
+    }
+
+    // Owner-only: forget every stored CID but keep the account itself
+    pub fn clear_history(&mut self, signer: &Pubkey) -> Result<(), ProgramError> {
+        if self.owner != *signer {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        if self.sealed {
+            return Err(CidError::AccountSealed.into());
+        }
+
+        self.cids.clear();
+        self.timestamps.clear();
+        self.labels.clear();
+        self.cid_count = 0;
+        self.latest_cid = String::new();
+        self.prev_cid = String::new();
+        self.expiry_slot = None;
+        self.claimed_size = None;
+        self.metadata.clear();
+
+        msg!("CID history cleared");
+        Ok(())
+    }
+
+    // Owner-only: check the account may be closed, before its rent is refunded.
+    // A sealed account is permanent.
+    pub fn close(&self, signer: &Pubkey) -> Result<(), ProgramError> {
+        if self.owner != *signer {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        if self.sealed {
+            return Err(CidError::AccountSealed.into());
+        }
+
+        msg!("Closing CID account with {} CIDs stored", self.cid_count);
+        Ok(())
+    }
+
+    // Store several CIDs in one operation. Either all of them are appended
+    // and cid_count grows by the batch size, or none are.
+    pub fn store_cids(&mut self, signer: &Pubkey, cids: Vec<String>, clock: &Clock) -> Result<(), ProgramError> {
+        self.write_cids(signer, cids, StoreExtras::default(), clock)
+    }
+
+    // Store a CID and seal the account in the same instruction, for one-shot
+    // publishing where the account should hold exactly one immutable CID
+    pub fn store_final(&mut self, signer: &Pubkey, cid: String, clock: &Clock) -> Result<(), ProgramError> {
+        self.write_cids(signer, vec![cid], StoreExtras::default(), clock)?;
+        self.sealed = true;
+
+        msg!("CID account sealed");
+        Ok(())
+    }
+
+    // Store a CID that reads as the latest only before `expiry_slot`; from
+    // that slot on, reads fall back to the CID it replaced
+    pub fn store_cid_temporary(&mut self, signer: &Pubkey, cid: String, expiry_slot: u64, clock: &Clock) -> Result<(), ProgramError> {
+        if expiry_slot <= clock.slot {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        self.write_cids(signer, vec![cid], StoreExtras::default(), clock)?;
+        self.expiry_slot = Some(expiry_slot);
+
+        msg!("CID expires at slot {}", expiry_slot);
+        Ok(())
+    }
+
+    // Owner-only: require at least `gap` slots between consecutive stores
+    pub fn set_min_slot_gap(&mut self, signer: &Pubkey, gap: u64) -> Result<(), ProgramError> {
+        if self.owner != *signer {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        self.min_slot_gap = gap;
+
+        msg!("Minimum slot gap set to {}", gap);
+        Ok(())
+    }
+
+    // Store on an org-owned bucket. The signer must be the org PDA rebuilt from
+    // the supplied seeds under the recorded authority program, which is how
+    // that program vouches for whoever it lets trigger the write.
+    pub fn store_cid_as_org(&mut self, signer: &Pubkey, namespace: &str, bump: u8, cid: String, clock: &Clock) -> Result<(), ProgramError> {
+        if self.org_namespace.as_deref() != Some(namespace) {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let authority_program = self.org_program.ok_or(ProgramError::InvalidSeeds)?;
+        if org_authority(namespace, bump, &authority_program)? != *signer {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        self.write_cids(signer, vec![cid], StoreExtras::default(), clock)
+    }
+
+    // Append one or more CIDs as a single all-or-nothing write: every CID and
+    // the account's limits are checked before anything is mutated
+    fn write_cids(&mut self, signer: &Pubkey, cids: Vec<String>, extras: StoreExtras, clock: &Clock) -> Result<(), ProgramError> {
+        if cids.is_empty() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        for cid in &cids {
+            if cid.len() > MAX_CID_LEN {
+                return Err(CidError::CidTooLong.into());
+            }
+            validate_cid(cid)?;
+        }
+
+        if self.owner != *signer {
+            return Err(CidError::Unauthorized.into());
+        }
+
+        if self.sealed {
+            return Err(CidError::AccountSealed.into());
+        }
+
+        if self.cids.len() + cids.len() > MAX_CIDS {
+            return Err(CidError::TooManyCids.into());
+        }
+
+        if !extras.allow_duplicates {
+            for (i, cid) in cids.iter().enumerate() {
+                if self.cids.contains(cid) || cids[..i].contains(cid) {
+                    return Err(CidError::DuplicateCid.into());
+                }
+            }
+        }
+
+        if let Some(last_slot) = self.last_write_slot {
+            if clock.slot < last_slot.saturating_add(self.min_slot_gap) {
+                return Err(CidError::WriteTooSoon.into());
+            }
+        }
+
+        self.prev_cid = self.effective_cid(clock).to_string();
+        self.expiry_slot = None;
+        self.cid_count += cids.len() as u64;
+        for cid in cids {
+            msg!("CID stored successfully: {}", cid);
+            self.latest_cid = cid.clone();
+            self.cids.push(cid);
+            self.timestamps.push(clock.unix_timestamp);
+            self.labels.push(extras.label.clone());
+        }
+        self.last_write_slot = Some(clock.slot);
+        self.last_updated = clock.unix_timestamp;
+        self.claimed_size = extras.claimed_size;
+        self.metadata = extras.metadata;
+
+        for (key, value) in &self.metadata {
+            msg!("  {}={}", key, value);
+        }
+        Ok(())
+    }
+}
+
+// Accounts are created through a CPI, which can allocate at most 10 KiB
+const _: () = assert!(CidAccount::SPACE <= MAX_PERMITTED_DATA_INCREASE);
+
+// Optional extras recorded alongside a store
+#[derive(Default)]
+struct StoreExtras {
+    label: String,
+    claimed_size: Option<u64>,
+    metadata: Vec<(String, String)>,
+    allow_duplicates: bool,
+}
+
+// Aggregate counts across the whole store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub total_accounts: usize,
+    pub total_cids: u64,
+    pub distinct_owners: usize,
 }
 
-// Storage manager
+// Off-chain mirror of CID accounts, keyed by account address. The program
+// keeps each CidAccount in its own PDA; this is the copy a server persists
+// as JSON to index, count and reconcile them without an RPC round trip per
+// account. The write methods apply the same rules as the on-chain
+// instructions so the mirror can be updated optimistically.
 #[derive(Serialize, Deserialize)]
 pub struct CidStorage {
     accounts: HashMap<String, CidAccount>,
+    // owner pubkey -> keys of the accounts it owns; rebuilt on load
+    #[serde(skip)]
+    owner_to_accounts: HashMap<String, Vec<String>>,
+}
+
+impl Default for CidStorage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CidStorage {
     pub fn new() -> Self {
         Self {
             accounts: HashMap::new(),
+            owner_to_accounts: HashMap::new(),
+        }
+    }
+
+    // Read storage from a data buffer: a little-endian u32 length followed by
+    // that many bytes of JSON. A zero length means no data yet.
+    //
+    // The JSON is a `{ "version": .., "data": .. }` envelope. Blobs without an
+    // envelope predate versioning and are read as version 1.
+    pub fn load(data: &[u8]) -> Result<Self, ProgramError> {
+        let len_bytes = data.get(..4).ok_or(ProgramError::AccountDataTooSmall)?;
+        let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]) as usize;
+
+        if len == 0 {
+            return Ok(Self::new());
         }
+
+        let json = data.get(4..4 + len).ok_or(ProgramError::InvalidAccountData)?;
+        let value: serde_json::Value = serde_json::from_slice(json)
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+
+        let (version, body) = match value {
+            serde_json::Value::Object(mut envelope) if envelope.contains_key("version") => {
+                let version = envelope.get("version").and_then(|v| v.as_u64())
+                    .ok_or(ProgramError::InvalidAccountData)?;
+                let body = envelope.remove("data").ok_or(ProgramError::InvalidAccountData)?;
+                (version, body)
+            }
+            legacy => (1, legacy),
+        };
+
+        let mut storage: Self = serde_json::from_value(body)
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+
+        match version {
+            1 => {
+                storage.migrate_v1();
+                storage.migrate_v2();
+                storage.migrate_v3();
+            }
+            2 => {
+                storage.migrate_v2();
+                storage.migrate_v3();
+            }
+            3 => storage.migrate_v3(),
+            v if v == u64::from(STORAGE_VERSION) => {}
+            v => {
+                msg!("Unsupported storage version: {}", v);
+                return Err(ProgramError::InvalidAccountData);
+            }
+        }
+
+        if !storage.accounts.values().all(CidAccount::is_aligned) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let owned: Vec<(Pubkey, String)> = storage.accounts.iter()
+            .map(|(key, cid_account)| (cid_account.owner, key.clone()))
+            .collect();
+        for (owner, key) in owned {
+            storage.index_owner(&owner, key);
+        }
+
+        Ok(storage)
+    }
+
+    // Write storage back into a data buffer using the `load` layout, always
+    // at the current STORAGE_VERSION
+    pub fn save(&self, data: &mut [u8]) -> Result<(), ProgramError> {
+        let envelope = serde_json::json!({ "version": STORAGE_VERSION, "data": self });
+        let json = serde_json::to_vec(&envelope).map_err(|_| ProgramError::InvalidAccountData)?;
+        let len = u32::try_from(json.len()).map_err(|_| ProgramError::AccountDataTooSmall)?;
+
+        if data.len() < 4 + json.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        data[..4].copy_from_slice(&len.to_le_bytes());
+        data[4..4 + json.len()].copy_from_slice(&json);
+        Ok(())
+    }
+
+    // Version 1 accounts only carried latest_cid; seed the history from it and
+    // mark the timestamps of anything already stored as unknown
+    fn migrate_v1(&mut self) {
+        for cid_account in self.accounts.values_mut() {
+            if cid_account.cids.is_empty() && !cid_account.latest_cid.is_empty() {
+                cid_account.cids.push(cid_account.latest_cid.clone());
+            }
+            let missing = cid_account.cids.len().saturating_sub(cid_account.timestamps.len());
+            cid_account.timestamps.splice(0..0, vec![0; missing]);
+        }
+        msg!("Migrated storage from version 1 to 2");
+    }
+
+    // Version 2 had no labels; everything already stored is unlabeled
+    fn migrate_v2(&mut self) {
+        for cid_account in self.accounts.values_mut() {
+            cid_account.labels.resize(cid_account.cids.len(), String::new());
+        }
+        msg!("Migrated storage from version 2 to 3");
     }
 
-    pub fn initialize(&mut self, account_key: Pubkey, owner: Pubkey) -> Result<(), ProgramError> {
+    // Version 3 did not record the seed owner; the best guess left is the
+    // current owner, which holds for every account never transferred
+    fn migrate_v3(&mut self) {
+        for cid_account in self.accounts.values_mut() {
+            if cid_account.seed_owner == Pubkey::default() {
+                cid_account.seed_owner = cid_account.owner;
+            }
+        }
+        msg!("Migrated storage from version 3 to 4");
+    }
+
+    // Mirror an Initialize. Like the program, only accepts the owner's own
+    // PDA: `(account_key, bump)` must be `cid_account_address(&owner)`.
+    pub fn initialize(&mut self, account_key: Pubkey, owner: Pubkey, bump: u8, clock: &Clock) -> Result<(), ProgramError> {
+        if (account_key, bump) != cid_account_address(&owner) {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        self.initialize_unchecked(account_key, owner, bump, clock)
+    }
+
+    // `initialize` without the address check, for mirrors keyed by something
+    // other than the program's PDAs, such as imported or test data
+    pub fn initialize_unchecked(&mut self, account_key: Pubkey, owner: Pubkey, bump: u8, clock: &Clock) -> Result<(), ProgramError> {
         let key_str = account_key.to_string();
         if self.accounts.contains_key(&key_str) {
             return Err(ProgramError::AccountAlreadyInitialized);
         }
 
-        let cid_account = CidAccount {
-            owner,
-            cid_count: 0,
-            latest_cid: String::new(),
-        };
+        let mut cid_account = CidAccount::new(owner, clock);
+        cid_account.bump = bump;
 
+        self.index_owner(&owner, key_str.clone());
         self.accounts.insert(key_str, cid_account);
         msg!("CID account initialized");
         Ok(())
     }
 
-    pub fn store_cid(&mut self, account_key: &str, signer: &Pubkey, cid: String) -> Result<(), ProgramError> {
-        let cid_account = self.accounts.get_mut(account_key)
-            .ok_or(ProgramError::UninitializedAccount)?;
-
-        if cid_account.owner != *signer {
-            return Err(ProgramError::InvalidAccountData);
+    // Initialize a bucket owned by the PDA of an organization namespace, at
+    // the CID account address of that PDA
+    pub fn initialize_org(&mut self, account_key: Pubkey, namespace: &str, bump: u8, authority_program: Pubkey, clock: &Clock) -> Result<(), ProgramError> {
+        let key_str = account_key.to_string();
+        if self.accounts.contains_key(&key_str) {
+            return Err(ProgramError::AccountAlreadyInitialized);
         }
 
-        cid_account.latest_cid = cid;
-        cid_account.cid_count += 1;
+        let mut cid_account = CidAccount::new_org(namespace, bump, authority_program, clock)?;
+        let (expected_key, cid_bump) = cid_account_address(&cid_account.owner);
+        if account_key != expected_key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        cid_account.bump = cid_bump;
 
-        msg!("CID stored successfully: {}", cid_account.latest_cid);
+        self.index_owner(&cid_account.owner, key_str.clone());
+        self.accounts.insert(key_str, cid_account);
+        msg!("CID account initialized for org: {}", namespace);
         Ok(())
     }
-}
 
-// Solana Smart Contract Entry Function
-pub fn process_instruction(
-    _program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    instruction_data: &[u8],
-) -> ProgramResult {
-    let accounts_iter = &mut accounts.iter();
-    let account_info = next_account_info(accounts_iter)?;
+    pub fn get_account(&self, account_key: &str) -> Option<&CidAccount> {
+        self.accounts.get(account_key)
+    }
 
-    msg!("Received instruction: {:?}", instruction_data);
+    fn account_mut(&mut self, account_key: &str) -> Result<&mut CidAccount, ProgramError> {
+        self.accounts.get_mut(account_key)
+            .ok_or(ProgramError::UninitializedAccount)
+    }
 
-    Ok(())
+    pub fn stats(&self) -> StorageStats {
+        StorageStats {
+            total_accounts: self.accounts.len(),
+            total_cids: self.accounts.values().map(|a| a.cid_count).sum(),
+            distinct_owners: self.owner_to_accounts.len(),
+        }
+    }
+
+    // Merge authoritative on-chain accounts (keyed by account address) into
+    // this copy: missing accounts are added and any local copy that differs
+    // from the chain is replaced. Remote accounts whose history vectors are
+    // misaligned are skipped. Returns how many discrepancies were fixed.
+    pub fn reconcile(&mut self, onchain: &[(Pubkey, CidAccount)]) -> usize {
+        let mut fixed = 0;
+
+        for (account_key, remote) in onchain {
+            let key_str = account_key.to_string();
+            if !remote.is_aligned() {
+                msg!("Reconcile: skipping account {} with misaligned history", key_str);
+                continue;
+            }
+
+            let stale_owner = match self.accounts.get(&key_str) {
+                None => {
+                    msg!("Reconcile: adding missing account {}", key_str);
+                    None
+                }
+                Some(local) if local != remote => {
+                    msg!("Reconcile: account {} is stale ({} -> {} CIDs)", key_str, local.cid_count, remote.cid_count);
+                    Some(local.owner)
+                }
+                Some(_) => continue,
+            };
+
+            if let Some(owner) = stale_owner {
+                self.unindex_owner(&owner, &key_str);
+            }
+            self.index_owner(&remote.owner, key_str.clone());
+            self.accounts.insert(key_str, remote.clone());
+            fixed += 1;
+        }
+
+        fixed
+    }
+
+    // CID at `index` in an account's history, oldest first
+    pub fn get_cid_by_index(&self, account_key: &str, index: u64) -> Result<&String, ProgramError> {
+        self.accounts.get(account_key)
+            .ok_or(ProgramError::UninitializedAccount)?
+            .get_cid_by_index(index)
+    }
+
+    // See CidAccount::history_page. An unknown account yields an empty page.
+    pub fn history_page(&self, account_key: &str, offset: usize, limit: usize) -> (Vec<String>, usize) {
+        self.accounts.get(account_key)
+            .map_or((Vec::new(), 0), |cid_account| cid_account.history_page(offset, limit))
+    }
+
+    // The CID readers of `account_key` should see at `clock`, with any
+    // expired temporary store already reverted
+    pub fn effective_cid(&self, account_key: &str, clock: &Clock) -> Option<&str> {
+        self.accounts.get(account_key).map(|cid_account| cid_account.effective_cid(clock))
+    }
+
+    // All accounts controlled by `owner`, ordered by account key
+    pub fn accounts_for_owner(&self, owner: &Pubkey) -> Vec<&CidAccount> {
+        self.owner_to_accounts.get(&owner.to_string())
+            .map(|keys| keys.iter().filter_map(|key| self.accounts.get(key)).collect())
+            .unwrap_or_default()
+    }
+
+    // Kept sorted so the order is the same however the index was built
+    fn index_owner(&mut self, owner: &Pubkey, account_key: String) {
+        let keys = self.owner_to_accounts.entry(owner.to_string()).or_default();
+        if let Err(index) = keys.binary_search(&account_key) {
+            keys.insert(index, account_key);
+        }
+    }
+
+    fn unindex_owner(&mut self, owner: &Pubkey, account_key: &str) {
+        let owner_key = owner.to_string();
+        if let Some(keys) = self.owner_to_accounts.get_mut(&owner_key) {
+            keys.retain(|key| key != account_key);
+            if keys.is_empty() {
+                self.owner_to_accounts.remove(&owner_key);
+            }
+        }
+    }
+
+    pub fn store_cid(&mut self, account_key: &str, signer: &Pubkey, cid: String, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_cid(signer, cid, clock)
+    }
+
+    pub fn store_cid_allow_duplicate(&mut self, account_key: &str, signer: &Pubkey, cid: String, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_cid_allow_duplicate(signer, cid, clock)
+    }
+
+    pub fn store_cid_labeled(&mut self, account_key: &str, signer: &Pubkey, cid: String, label: String, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_cid_labeled(signer, cid, label, clock)
+    }
+
+    pub fn store_cid_with_size(&mut self, account_key: &str, signer: &Pubkey, cid: String, claimed_size: u64, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_cid_with_size(signer, cid, claimed_size, clock)
+    }
+
+    pub fn store_cid_with_kv(&mut self, account_key: &str, signer: &Pubkey, cid: String, kv: Vec<(String, String)>, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_cid_with_kv(signer, cid, kv, clock)
+    }
+
+    pub fn set_recovery_key(&mut self, account_key: &str, signer: &Pubkey, recovery_key: Option<Pubkey>, delay: i64) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.set_recovery_key(signer, recovery_key, delay)
+    }
+
+    pub fn request_recovery(&mut self, account_key: &str, signer: &Pubkey, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.request_recovery(signer, clock)
+    }
+
+    pub fn recover(&mut self, account_key: &str, signer: &Pubkey, new_owner: Pubkey, clock: &Clock) -> Result<(), ProgramError> {
+        let cid_account = self.account_mut(account_key)?;
+        let old_owner = cid_account.owner;
+        cid_account.recover(signer, new_owner, clock)?;
+
+        self.unindex_owner(&old_owner, account_key);
+        self.index_owner(&new_owner, account_key.to_string());
+        Ok(())
+    }
+
+    pub fn transfer_ownership(&mut self, account_key: &str, signer: &Pubkey, new_owner: Pubkey) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.transfer_ownership(signer, new_owner)?;
+
+        self.unindex_owner(signer, account_key);
+        self.index_owner(&new_owner, account_key.to_string());
+        Ok(())
+    }
+
+    pub fn remove_cid(&mut self, account_key: &str, signer: &Pubkey, cid: &str) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.remove_cid(signer, cid)
+    }
+
+    pub fn clear_history(&mut self, account_key: &str, signer: &Pubkey) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.clear_history(signer)
+    }
+
+    // Owner-only: drop the account entry entirely, returning what it held
+    pub fn close_account(&mut self, account_key: &str, signer: &Pubkey) -> Result<CidAccount, ProgramError> {
+        self.account_mut(account_key)?.close(signer)?;
+
+        self.unindex_owner(signer, account_key);
+        self.accounts.remove(account_key).ok_or(ProgramError::UninitializedAccount)
+    }
+
+    pub fn store_cids(&mut self, account_key: &str, signer: &Pubkey, cids: Vec<String>, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_cids(signer, cids, clock)
+    }
+
+    pub fn store_final(&mut self, account_key: &str, signer: &Pubkey, cid: String, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_final(signer, cid, clock)
+    }
+
+    pub fn store_cid_temporary(&mut self, account_key: &str, signer: &Pubkey, cid: String, expiry_slot: u64, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_cid_temporary(signer, cid, expiry_slot, clock)
+    }
+
+    pub fn set_min_slot_gap(&mut self, account_key: &str, signer: &Pubkey, gap: u64) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.set_min_slot_gap(signer, gap)
+    }
+
+    pub fn store_cid_as_org(&mut self, account_key: &str, signer: &Pubkey, namespace: &str, bump: u8, cid: String, clock: &Clock) -> Result<(), ProgramError> {
+        self.account_mut(account_key)?.store_cid_as_org(signer, namespace, bump, cid, clock)
+    }
+}
+
+// Accept a CIDv0 (base58 "Qm...", 46 chars) or a CIDv1 in lowercase base32
+// ("b..."), rejecting anything empty, truncated or outside those alphabets
+pub fn validate_cid(cid: &str) -> Result<(), CidError> {
+    let valid = if cid.starts_with("Qm") {
+        cid.len() == CIDV0_LEN && cid.chars().all(|c| BASE58_ALPHABET.contains(c))
+    } else if let Some(body) = cid.strip_prefix('b') {
+        cid.len() >= CIDV1_MIN_LEN
+            && body.chars().all(|c| c.is_ascii_lowercase() || ('2'..='7').contains(&c))
+    } else {
+        false
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(CidError::InvalidCid)
+    }
+}
+
+// Derive the CID account address an owner initializes. The address does not
+// move with TransferOwnership or Recover: it stays derived from the account's
+// `seed_owner`, and the new owner is free to Initialize a bucket of their own.
+pub fn cid_account_address(owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CID_SEED, owner.as_ref()], &ID)
+}
+
+// Derive the PDA that acts as owner for an organization namespace. It lives
+// under the org's authority program, the only program that can sign for it.
+pub fn org_authority(namespace: &str, bump: u8, authority_program: &Pubkey) -> Result<Pubkey, ProgramError> {
+    Pubkey::create_program_address(&[ORG_SEED, namespace.as_bytes(), &[bump]], authority_program)
+        .map_err(|_| ProgramError::InvalidSeeds)
+}
+
+// Take the next account and require that it signed the transaction
+fn next_signer<'a, 'b>(
+    accounts_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+) -> Result<&'a AccountInfo<'b>, ProgramError> {
+    let signer_info = next_account_info(accounts_iter)?;
+    if !signer_info.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    Ok(signer_info)
+}
+
+// Create the CID account PDA with CidAccount::SPACE bytes, rent paid by
+// `payer_info`, signing for the new address with its own seeds. Anyone can
+// send lamports to the address beforehand, which makes CreateAccount fail,
+// so a funded address is topped up and claimed piecewise instead.
+fn create_cid_account<'a>(
+    program_id: &Pubkey,
+    cid_account_info: &AccountInfo<'a>,
+    payer_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    if cid_account_info.owner == program_id {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    if !system_program::check_id(system_program_info.key) {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let lamports = Rent::get()?.minimum_balance(CidAccount::SPACE);
+    if cid_account_info.lamports() == 0 {
+        return invoke_signed(
+            &create_account(payer_info.key, cid_account_info.key, lamports, CidAccount::SPACE as u64, program_id),
+            &[payer_info.clone(), cid_account_info.clone(), system_program_info.clone()],
+            &[seeds],
+        );
+    }
+
+    let shortfall = lamports.saturating_sub(cid_account_info.lamports());
+    if shortfall > 0 {
+        invoke(
+            &transfer(payer_info.key, cid_account_info.key, shortfall),
+            &[payer_info.clone(), cid_account_info.clone(), system_program_info.clone()],
+        )?;
+    }
+    invoke_signed(
+        &allocate(cid_account_info.key, CidAccount::SPACE as u64),
+        &[cid_account_info.clone(), system_program_info.clone()],
+        &[seeds],
+    )?;
+    invoke_signed(
+        &assign(cid_account_info.key, program_id),
+        &[cid_account_info.clone(), system_program_info.clone()],
+        &[seeds],
+    )
+}
+
+// Decode the CID account an instruction works on, once it is known to
+// belong to this program and to sit at the PDA its seed owner and bump derive
+fn load_cid_account(program_id: &Pubkey, cid_account_info: &AccountInfo) -> Result<CidAccount, ProgramError> {
+    if cid_account_info.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let cid_account = CidAccount::try_from_account_data(&cid_account_info.try_borrow_data()?)?;
+    let expected_key = Pubkey::create_program_address(
+        &[CID_SEED, cid_account.seed_owner.as_ref(), &[cid_account.bump]],
+        program_id,
+    ).map_err(|_| ProgramError::InvalidSeeds)?;
+    if *cid_account_info.key != expected_key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    Ok(cid_account)
+}
+
+// Create the owner's CID account PDA and write a fresh CidAccount into it,
+// with the recovery key and delay in `recovery` set up front if given
+fn initialize_owner_account<'a, 'b>(
+    program_id: &Pubkey,
+    cid_account_info: &AccountInfo<'b>,
+    accounts_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+    owner: Pubkey,
+    recovery: Option<(Pubkey, i64)>,
+) -> ProgramResult {
+    let signer_info = next_signer(accounts_iter)?;
+    if *signer_info.key != owner {
+        return Err(CidError::Unauthorized.into());
+    }
+
+    let (expected_key, bump) = cid_account_address(&owner);
+    if *cid_account_info.key != expected_key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let clock = Clock::get()?;
+    let mut cid_account = CidAccount::new(owner, &clock);
+    cid_account.bump = bump;
+    if let Some((recovery_key, delay)) = recovery {
+        cid_account.set_recovery_key(&owner, Some(recovery_key), delay)?;
+    }
+
+    let system_program_info = next_account_info(accounts_iter)?;
+    create_cid_account(program_id, cid_account_info, signer_info, system_program_info, &[CID_SEED, owner.as_ref(), &[bump]])?;
+    cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+    msg!("CID account initialized");
+    CidEvent::AccountInitialized { account: *cid_account_info.key, owner }.emit()
+}
+
+// Solana Smart Contract Entry Function
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let instruction = CidInstruction::try_from_slice(instruction_data)
+        .map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let accounts_iter = &mut accounts.iter();
+    let cid_account_info = next_account_info(accounts_iter)?;
+
+    match instruction {
+        CidInstruction::Initialize { owner } => {
+            msg!("Instruction: Initialize");
+            initialize_owner_account(program_id, cid_account_info, accounts_iter, owner, None)?;
+        }
+        CidInstruction::StoreCid { cid } => {
+            msg!("Instruction: StoreCid");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.store_cid(signer_info.key, cid.clone(), &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid, count: cid_account.cid_count }.emit()?;
+        }
+        CidInstruction::RemoveCid { cid } => {
+            msg!("Instruction: RemoveCid");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            cid_account.remove_cid(signer_info.key, &cid)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+        }
+        CidInstruction::CloseAccount => {
+            msg!("Instruction: CloseAccount");
+            let signer_info = next_signer(accounts_iter)?;
+            let cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            cid_account.close(signer_info.key)?;
+
+            // Refund the rent to the owner; the runtime purges an account
+            // left with no lamports at the end of the transaction
+            let refund = cid_account_info.lamports();
+            let balance = signer_info.lamports().checked_add(refund)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            **signer_info.try_borrow_mut_lamports()? = balance;
+            **cid_account_info.try_borrow_mut_lamports()? = 0;
+            cid_account_info.try_borrow_mut_data()?.fill(0);
+        }
+        CidInstruction::StoreCids { cids } => {
+            msg!("Instruction: StoreCids");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.store_cids(signer_info.key, cids.clone(), &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            let first_count = cid_account.cid_count + 1 - cids.len() as u64;
+            for (cid, count) in cids.into_iter().zip(first_count..) {
+                CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid, count }.emit()?;
+            }
+        }
+        CidInstruction::TransferOwnership { new_owner } => {
+            msg!("Instruction: TransferOwnership");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            cid_account.transfer_ownership(signer_info.key, new_owner)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::OwnershipTransferred { account: *cid_account_info.key, old_owner: *signer_info.key, new_owner }.emit()?;
+        }
+        CidInstruction::GetCidAt { index } => {
+            msg!("Instruction: GetCidAt");
+            let cid_account = load_cid_account(program_id, cid_account_info)?;
+            let cid = cid_account.get_cid_by_index(index)?;
+
+            msg!("CID at {}: {}", index, cid);
+            set_return_data(cid.as_bytes());
+        }
+        CidInstruction::StoreCidLabeled { cid, label } => {
+            msg!("Instruction: StoreCidLabeled");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.store_cid_labeled(signer_info.key, cid.clone(), label, &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid, count: cid_account.cid_count }.emit()?;
+        }
+        CidInstruction::ClearHistory => {
+            msg!("Instruction: ClearHistory");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            cid_account.clear_history(signer_info.key)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::HistoryCleared { account: *cid_account_info.key, owner: *signer_info.key }.emit()?;
+        }
+        CidInstruction::StoreCidWithSize { cid, claimed_size } => {
+            msg!("Instruction: StoreCidWithSize");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.store_cid_with_size(signer_info.key, cid.clone(), claimed_size, &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid, count: cid_account.cid_count }.emit()?;
+        }
+        CidInstruction::SetRecoveryKey { recovery_key, delay } => {
+            msg!("Instruction: SetRecoveryKey");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            cid_account.set_recovery_key(signer_info.key, recovery_key, delay)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+        }
+        CidInstruction::RequestRecovery => {
+            msg!("Instruction: RequestRecovery");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.request_recovery(signer_info.key, &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::RecoveryRequested {
+                account: *cid_account_info.key,
+                recovery_key: *signer_info.key,
+                unlocks_at: clock.unix_timestamp.saturating_add(cid_account.recovery_delay),
+            }.emit()?;
+        }
+        CidInstruction::Recover { new_owner } => {
+            msg!("Instruction: Recover");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let old_owner = cid_account.owner;
+            let clock = Clock::get()?;
+            cid_account.recover(signer_info.key, new_owner, &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::OwnershipTransferred { account: *cid_account_info.key, old_owner, new_owner }.emit()?;
+        }
+        CidInstruction::StoreCidWithKv { cid, kv } => {
+            msg!("Instruction: StoreCidWithKv");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.store_cid_with_kv(signer_info.key, cid.clone(), kv.clone(), &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid: cid.clone(), count: cid_account.cid_count }.emit()?;
+            CidEvent::MetadataAttached { account: *cid_account_info.key, cid, kv }.emit()?;
+        }
+        CidInstruction::InitializeOrg { namespace, bump, authority_program } => {
+            msg!("Instruction: InitializeOrg");
+            let org_info = next_signer(accounts_iter)?;
+            let payer_info = next_signer(accounts_iter)?;
+
+            let clock = Clock::get()?;
+            let cid_account = CidAccount::new_org(&namespace, bump, authority_program, &clock)?;
+            if *org_info.key != cid_account.owner {
+                return Err(ProgramError::InvalidSeeds);
+            }
+
+            let (expected_key, cid_bump) = cid_account_address(org_info.key);
+            if *cid_account_info.key != expected_key {
+                return Err(ProgramError::InvalidSeeds);
+            }
+
+            let system_program_info = next_account_info(accounts_iter)?;
+            create_cid_account(program_id, cid_account_info, payer_info, system_program_info, &[CID_SEED, org_info.key.as_ref(), &[cid_bump]])?;
+
+            let cid_account = CidAccount { bump: cid_bump, ..cid_account };
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            msg!("CID account initialized for org: {}", namespace);
+            CidEvent::AccountInitialized { account: *cid_account_info.key, owner: *org_info.key }.emit()?;
+        }
+        CidInstruction::StoreCidAsOrg { namespace, bump, cid } => {
+            msg!("Instruction: StoreCidAsOrg");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.store_cid_as_org(signer_info.key, &namespace, bump, cid.clone(), &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid, count: cid_account.cid_count }.emit()?;
+        }
+        CidInstruction::SetMinSlotGap { gap } => {
+            msg!("Instruction: SetMinSlotGap");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            cid_account.set_min_slot_gap(signer_info.key, gap)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+        }
+        CidInstruction::StoreFinal { cid } => {
+            msg!("Instruction: StoreFinal");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.store_final(signer_info.key, cid.clone(), &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid, count: cid_account.cid_count }.emit()?;
+        }
+        CidInstruction::StoreCidTemporary { cid, expiry_slot } => {
+            msg!("Instruction: StoreCidTemporary");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            cid_account.store_cid_temporary(signer_info.key, cid.clone(), expiry_slot, &clock)?;
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid, count: cid_account.cid_count }.emit()?;
+        }
+        CidInstruction::StoreCidWithFlags { cid, allow_duplicates } => {
+            msg!("Instruction: StoreCidWithFlags");
+            let signer_info = next_signer(accounts_iter)?;
+            let mut cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            if allow_duplicates {
+                cid_account.store_cid_allow_duplicate(signer_info.key, cid.clone(), &clock)?;
+            } else {
+                cid_account.store_cid(signer_info.key, cid.clone(), &clock)?;
+            }
+            cid_account.pack_into(&mut cid_account_info.try_borrow_mut_data()?)?;
+
+            CidEvent::CidStored { account: *cid_account_info.key, owner: *signer_info.key, cid, count: cid_account.cid_count }.emit()?;
+        }
+        CidInstruction::InitializeWithRecovery { owner, recovery_key, delay } => {
+            msg!("Instruction: InitializeWithRecovery");
+            initialize_owner_account(program_id, cid_account_info, accounts_iter, owner, Some((recovery_key, delay)))?;
+        }
+        CidInstruction::GetEffectiveCid => {
+            msg!("Instruction: GetEffectiveCid");
+            let cid_account = load_cid_account(program_id, cid_account_info)?;
+
+            let clock = Clock::get()?;
+            let cid = cid_account.effective_cid(&clock);
+
+            msg!("Effective CID: {}", cid);
+            set_return_data(cid.as_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::{
+        entrypoint::SUCCESS,
+        instruction::Instruction,
+        program_stubs::{set_syscall_stubs, SyscallStubs},
+    };
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    const CIDV0: &str = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+
+    thread_local! {
+        static CLOCK: RefCell<Clock> = RefCell::new(Clock::default());
+        static RETURN_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static EVENTS: RefCell<Vec<CidEvent>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Syscalls the program makes, answered from the thread-locals above so
+    // tests running in parallel do not see each other's clock or output
+    struct TestSyscalls;
+
+    impl SyscallStubs for TestSyscalls {
+        fn sol_log(&self, _message: &str) {}
+
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = CLOCK.with(|clock| clock.borrow().clone());
+            unsafe { std::ptr::write(var_addr as *mut Clock, clock) };
+            SUCCESS
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { std::ptr::write(var_addr as *mut Rent, Rent::default()) };
+            SUCCESS
+        }
+
+        // The program only calls into the system program, to create (or top
+        // up, allocate and assign) its PDA. Check each call signs for the PDA
+        // it touches and move lamports as the system program would; the test
+        // accounts already have their space allocated.
+        fn sol_invoke_signed(&self, instruction: &Instruction, account_infos: &[AccountInfo], signers_seeds: &[&[&[u8]]]) -> ProgramResult {
+            assert_eq!(instruction.program_id, system_program::ID);
+            let tag = u32::from_le_bytes(instruction.data[..4].try_into().unwrap());
+            match tag {
+                // CreateAccount and Transfer
+                0 | 2 => {
+                    let (payer_info, new_account_info) = (&account_infos[0], &account_infos[1]);
+                    if tag == 0 {
+                        assert_eq!(Pubkey::create_program_address(signers_seeds[0], &ID), Ok(*new_account_info.key));
+                        if new_account_info.lamports() > 0 {
+                            // SystemError::AccountAlreadyInUse
+                            return Err(ProgramError::Custom(0));
+                        }
+                    }
+
+                    let lamports = u64::from_le_bytes(instruction.data[4..12].try_into().unwrap());
+                    **payer_info.try_borrow_mut_lamports()? -= lamports;
+                    **new_account_info.try_borrow_mut_lamports()? += lamports;
+                }
+                // Assign and Allocate
+                1 | 8 => {
+                    assert_eq!(Pubkey::create_program_address(signers_seeds[0], &ID), Ok(*account_infos[0].key));
+                }
+                _ => panic!("unexpected system instruction {}", tag),
+            }
+            Ok(())
+        }
+
+        fn sol_set_return_data(&self, data: &[u8]) {
+            RETURN_DATA.with(|return_data| *return_data.borrow_mut() = data.to_vec());
+        }
+
+        fn sol_log_data(&self, fields: &[&[u8]]) {
+            let event = CidEvent::try_from_slice(fields[0]).unwrap();
+            EVENTS.with(|events| events.borrow_mut().push(event));
+        }
+    }
+
+    fn set_clock(slot: u64, unix_timestamp: i64) {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            set_syscall_stubs(Box::new(TestSyscalls));
+        });
+        CLOCK.with(|clock| *clock.borrow_mut() = clock_at(slot, unix_timestamp));
+    }
+
+    fn clock_at(slot: u64, unix_timestamp: i64) -> Clock {
+        Clock { slot, unix_timestamp, ..Clock::default() }
+    }
+
+    fn take_events() -> Vec<CidEvent> {
+        EVENTS.with(|events| events.borrow_mut().drain(..).collect())
+    }
+
+    // A distinct, valid 59-character CIDv1 for each `n`
+    fn cid(n: usize) -> String {
+        let digits: String = format!("{:052}", n).bytes().map(|d| (b'a' + d - b'0') as char).collect();
+        format!("bafybei{}", digits)
+    }
+
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+        is_signer: bool,
+    }
+
+    impl TestAccount {
+        fn wallet(key: Pubkey) -> Self {
+            Self { key, owner: system_program::ID, lamports: 1_000_000_000, data: Vec::new(), is_signer: true }
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(&self.key, self.is_signer, true, &mut self.lamports, &mut self.data, &self.owner, false, 0)
+        }
+    }
+
+    // An owner wallet and its CID account PDA, with room allocated the way
+    // the system program would on CreateAccount
+    struct TestEnv {
+        cid_account: TestAccount,
+        owner: TestAccount,
+        system_program: TestAccount,
+    }
+
+    impl TestEnv {
+        fn new() -> Self {
+            Self::for_owner(Pubkey::new_unique())
+        }
+
+        fn for_owner(owner: Pubkey) -> Self {
+            set_clock(1, 1_700_000_000);
+            let owner = TestAccount::wallet(owner);
+            let (cid_account_key, _) = cid_account_address(&owner.key);
+            Self {
+                cid_account: TestAccount {
+                    key: cid_account_key,
+                    owner: system_program::ID,
+                    lamports: 0,
+                    data: vec![0; CidAccount::SPACE],
+                    is_signer: false,
+                },
+                owner,
+                system_program: TestAccount {
+                    key: system_program::ID,
+                    owner: Pubkey::default(),
+                    lamports: 1,
+                    data: Vec::new(),
+                    is_signer: false,
+                },
+            }
+        }
+
+        fn initialized() -> Self {
+            let mut env = Self::new();
+            env.initialize().unwrap();
+            env
+        }
+
+        fn initialize(&mut self) -> ProgramResult {
+            let owner = self.owner.key;
+            let result = run(CidInstruction::Initialize { owner }, &mut [&mut self.cid_account, &mut self.owner, &mut self.system_program]);
+            if result.is_ok() {
+                // Ownership moves to the program as part of CreateAccount
+                self.cid_account.owner = ID;
+            }
+            result
+        }
+
+        fn send(&mut self, instruction: CidInstruction) -> ProgramResult {
+            run(instruction, &mut [&mut self.cid_account, &mut self.owner])
+        }
+
+        fn send_as(&mut self, signer: &mut TestAccount, instruction: CidInstruction) -> ProgramResult {
+            run(instruction, &mut [&mut self.cid_account, signer])
+        }
+
+        fn account(&self) -> CidAccount {
+            CidAccount::try_from_account_data(&self.cid_account.data).unwrap()
+        }
+    }
+
+    fn run(instruction: CidInstruction, accounts: &mut [&mut TestAccount]) -> ProgramResult {
+        let infos: Vec<AccountInfo> = accounts.iter_mut().map(|account| account.info()).collect();
+        process_instruction(&ID, &infos, &borsh::to_vec(&instruction).unwrap())
+    }
+
+    fn new_account(owner: Pubkey) -> CidAccount {
+        CidAccount::new(owner, &clock_at(1, 1_700_000_000))
+    }
+
+    #[test]
+    fn initialize_creates_the_owner_pda() {
+        let mut env = TestEnv::new();
+        env.initialize().unwrap();
+
+        let cid_account = env.account();
+        let (expected_key, bump) = Pubkey::find_program_address(&[b"cid", env.owner.key.as_ref()], &ID);
+        assert_eq!(env.cid_account.key, expected_key);
+        assert_eq!(cid_account.owner, env.owner.key);
+        assert_eq!(cid_account.bump, bump);
+        assert_eq!(env.cid_account.lamports, Rent::default().minimum_balance(CidAccount::SPACE));
+        assert_eq!(take_events(), vec![CidEvent::AccountInitialized { account: env.cid_account.key, owner: env.owner.key }]);
+    }
+
+    #[test]
+    fn initialize_requires_the_owner_signature() {
+        let mut env = TestEnv::new();
+        env.owner.is_signer = false;
+        assert_eq!(env.initialize(), Err(ProgramError::MissingRequiredSignature));
+
+        let mut env = TestEnv::new();
+        let mut other = TestAccount::wallet(Pubkey::new_unique());
+        let owner = env.owner.key;
+        let result = run(CidInstruction::Initialize { owner }, &mut [&mut env.cid_account, &mut other, &mut env.system_program]);
+        assert_eq!(result, Err(CidError::Unauthorized.into()));
+    }
+
+    #[test]
+    fn initialize_rejects_a_second_call_and_a_foreign_address() {
+        let mut env = TestEnv::initialized();
+        assert_eq!(env.initialize(), Err(ProgramError::AccountAlreadyInitialized));
+
+        let mut env = TestEnv::new();
+        env.cid_account.key = Pubkey::new_unique();
+        assert_eq!(env.initialize(), Err(ProgramError::InvalidSeeds));
+    }
+
+    #[test]
+    fn initialize_claims_a_prefunded_address() {
+        let rent = Rent::default().minimum_balance(CidAccount::SPACE);
+        let mut env = TestEnv::new();
+        env.cid_account.lamports = 1_000;
+        let balance = env.owner.lamports;
+        env.initialize().unwrap();
+        assert_eq!(env.cid_account.lamports, rent);
+        assert_eq!(env.owner.lamports, balance - (rent - 1_000));
+        assert_eq!(env.account().owner, env.owner.key);
+
+        // Nothing to top up when the address already holds the rent
+        let mut env = TestEnv::new();
+        env.cid_account.lamports = rent + 1;
+        let balance = env.owner.lamports;
+        env.initialize().unwrap();
+        assert_eq!(env.cid_account.lamports, rent + 1);
+        assert_eq!(env.owner.lamports, balance);
+    }
+
+    #[test]
+    fn instructions_reject_an_account_off_its_pda() {
+        let mut env = TestEnv::initialized();
+        let mut copy = TestAccount {
+            key: Pubkey::new_unique(),
+            owner: ID,
+            lamports: env.cid_account.lamports,
+            data: env.cid_account.data.clone(),
+            is_signer: false,
+        };
+        let store = CidInstruction::StoreCid { cid: cid(1) };
+        assert_eq!(run(store.clone(), &mut [&mut copy, &mut env.owner]), Err(ProgramError::InvalidSeeds));
+
+        // A stored bump that does not derive the account's own address
+        let mut cid_account = env.account();
+        cid_account.bump = cid_account.bump.wrapping_sub(1);
+        cid_account.pack_into(&mut env.cid_account.data).unwrap();
+        assert_eq!(env.send(store), Err(ProgramError::InvalidSeeds));
+    }
+
+    #[test]
+    fn unknown_instruction_is_rejected() {
+        let mut env = TestEnv::initialized();
+        let infos = [env.cid_account.info(), env.owner.info()];
+        assert_eq!(process_instruction(&ID, &infos, &[0xff]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn store_cid_round_trips_through_process_instruction() {
+        let mut env = TestEnv::initialized();
+        take_events();
+        env.send(CidInstruction::StoreCid { cid: CIDV0.to_string() }).unwrap();
+
+        let cid_account = env.account();
+        assert_eq!(cid_account.latest_cid, CIDV0);
+        assert_eq!(cid_account.cid_count, 1);
+        assert_eq!(take_events(), vec![CidEvent::CidStored {
+            account: env.cid_account.key,
+            owner: env.owner.key,
+            cid: CIDV0.to_string(),
+            count: 1,
+        }]);
+    }
+
+    #[test]
+    fn store_cid_rejects_a_non_owner() {
+        let mut env = TestEnv::initialized();
+        let mut intruder = TestAccount::wallet(Pubkey::new_unique());
+        assert_eq!(env.send_as(&mut intruder, CidInstruction::StoreCid { cid: cid(1) }), Err(CidError::Unauthorized.into()));
+
+        env.owner.is_signer = false;
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: cid(1) }), Err(ProgramError::MissingRequiredSignature));
+
+        env.owner.is_signer = true;
+        env.send(CidInstruction::StoreCid { cid: cid(1) }).unwrap();
+        assert_eq!(env.account().cids, vec![cid(1)]);
+    }
+
+    #[test]
+    fn store_cid_keeps_the_full_history() {
+        let mut env = TestEnv::initialized();
+        env.send(CidInstruction::StoreCid { cid: cid(1) }).unwrap();
+        env.send(CidInstruction::StoreCid { cid: cid(2) }).unwrap();
+
+        let cid_account = env.account();
+        assert_eq!(cid_account.cids, vec![cid(1), cid(2)]);
+        assert_eq!(cid_account.latest_cid, cid(2));
+        assert_eq!(cid_account.get_cid_at(0), Some(&cid(1)));
+    }
+
+    #[test]
+    fn remove_cid_drops_the_entry() {
+        let mut env = TestEnv::initialized();
+        env.send(CidInstruction::StoreCids { cids: vec![cid(1), cid(2)] }).unwrap();
+
+        env.send(CidInstruction::RemoveCid { cid: cid(2) }).unwrap();
+        let cid_account = env.account();
+        assert_eq!(cid_account.cids, vec![cid(1)]);
+        assert_eq!(cid_account.latest_cid, cid(1));
+        assert_eq!(cid_account.cid_count, 1);
+
+        assert_eq!(env.send(CidInstruction::RemoveCid { cid: cid(2) }), Err(CidError::CidNotFound.into()));
+    }
+
+    #[test]
+    fn close_account_refunds_the_owner() {
+        let mut env = TestEnv::initialized();
+        let mut intruder = TestAccount::wallet(Pubkey::new_unique());
+        assert_eq!(env.send_as(&mut intruder, CidInstruction::CloseAccount), Err(CidError::Unauthorized.into()));
+
+        let rent = env.cid_account.lamports;
+        let balance = env.owner.lamports;
+        env.send(CidInstruction::CloseAccount).unwrap();
+
+        assert_eq!(env.cid_account.lamports, 0);
+        assert_eq!(env.owner.lamports, balance + rent);
+        assert!(env.cid_account.data.iter().all(|&b| b == 0));
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: cid(1) }), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn validate_cid_accepts_v0_and_v1_only() {
+        assert_eq!(CIDV0.len(), 46);
+        assert_eq!(validate_cid(CIDV0), Ok(()));
+        assert_eq!(validate_cid(&cid(7)), Ok(()));
+        assert_eq!(validate_cid(""), Err(CidError::InvalidCid));
+        assert_eq!(validate_cid(&CIDV0[..40]), Err(CidError::InvalidCid));
+        assert_eq!(validate_cid(&cid(7)[..40]), Err(CidError::InvalidCid));
+        assert_eq!(validate_cid("QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPb0l"), Err(CidError::InvalidCid));
+    }
+
+    #[test]
+    fn store_cid_rejects_invalid_cids() {
+        let mut env = TestEnv::initialized();
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: String::new() }), Err(CidError::InvalidCid.into()));
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: CIDV0[..20].to_string() }), Err(CidError::InvalidCid.into()));
+        assert_eq!(env.account().cid_count, 0);
+    }
+
+    #[test]
+    fn cid_length_is_bounded() {
+        let mut env = TestEnv::initialized();
+        let longest = format!("b{}", "a".repeat(MAX_CID_LEN - 1));
+        env.send(CidInstruction::StoreCid { cid: longest.clone() }).unwrap();
+        assert_eq!(env.account().latest_cid, longest);
+
+        let too_long = format!("b{}", "a".repeat(MAX_CID_LEN));
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: too_long }), Err(CidError::CidTooLong.into()));
+    }
+
+    #[test]
+    fn history_is_bounded_by_max_cids() {
+        let mut env = TestEnv::initialized();
+        env.send(CidInstruction::StoreCids { cids: (0..MAX_CIDS).map(cid).collect() }).unwrap();
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: cid(MAX_CIDS) }), Err(CidError::TooManyCids.into()));
+    }
+
+    #[test]
+    fn store_cids_appends_a_batch() {
+        let mut env = TestEnv::initialized();
+        take_events();
+        let batch: Vec<String> = (1..=5).map(cid).collect();
+        env.send(CidInstruction::StoreCids { cids: batch.clone() }).unwrap();
+
+        let cid_account = env.account();
+        assert_eq!(cid_account.cids, batch);
+        assert_eq!(cid_account.cid_count, 5);
+        let counts: Vec<u64> = take_events().into_iter().map(|event| match event {
+            CidEvent::CidStored { count, .. } => count,
+            other => panic!("unexpected event {:?}", other),
+        }).collect();
+        assert_eq!(counts, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn store_cids_rejects_the_whole_batch_on_one_bad_entry() {
+        let mut env = TestEnv::initialized();
+        let mut batch: Vec<String> = (1..=5).map(cid).collect();
+        batch[3] = "not-a-cid".to_string();
+
+        assert_eq!(env.send(CidInstruction::StoreCids { cids: batch }), Err(CidError::InvalidCid.into()));
+        let cid_account = env.account();
+        assert!(cid_account.cids.is_empty());
+        assert_eq!(cid_account.cid_count, 0);
+    }
+
+    #[test]
+    fn timestamps_track_creation_and_updates() {
+        let mut env = TestEnv::initialized();
+        set_clock(10, 1_700_000_500);
+        env.send(CidInstruction::StoreCid { cid: cid(1) }).unwrap();
+
+        let cid_account = env.account();
+        assert_eq!(cid_account.created_at, 1_700_000_000);
+        assert_eq!(cid_account.last_updated, 1_700_000_500);
+        assert!(cid_account.last_updated >= cid_account.created_at);
+        assert_eq!(cid_account.timestamps, vec![1_700_000_500]);
+    }
+
+    #[test]
+    fn duplicates_are_rejected_unless_allowed() {
+        let clock = clock_at(1, 0);
+        let owner = Pubkey::new_unique();
+        let mut cid_account = new_account(owner);
+        cid_account.store_cid(&owner, cid(1), &clock).unwrap();
+
+        assert_eq!(cid_account.store_cid(&owner, cid(1), &clock), Err(CidError::DuplicateCid.into()));
+        assert_eq!(cid_account.store_cids(&owner, vec![cid(2), cid(2)], &clock), Err(CidError::DuplicateCid.into()));
+        assert_eq!(cid_account.cid_count, 1);
+
+        cid_account.store_cid_allow_duplicate(&owner, cid(1), &clock).unwrap();
+        assert_eq!(cid_account.cids, vec![cid(1), cid(1)]);
+        assert_eq!(cid_account.cid_count, 2);
+    }
+
+    #[test]
+    fn store_cid_with_flags_instruction_opts_into_duplicates() {
+        let mut env = TestEnv::initialized();
+        let store = |allow_duplicates: bool| CidInstruction::StoreCidWithFlags { cid: cid(1), allow_duplicates };
+        env.send(store(false)).unwrap();
+
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: cid(1) }), Err(CidError::DuplicateCid.into()));
+        assert_eq!(env.send(store(false)), Err(CidError::DuplicateCid.into()));
+        assert_eq!(env.account().cid_count, 1);
+
+        env.send(store(true)).unwrap();
+        let cid_account = env.account();
+        assert_eq!(cid_account.cids, vec![cid(1), cid(1)]);
+        assert_eq!(cid_account.cid_count, 2);
+    }
+
+    #[test]
+    fn transfer_ownership_moves_write_access() {
+        let mut env = TestEnv::initialized();
+        let mut new_owner = TestAccount::wallet(Pubkey::new_unique());
+        let mut intruder = TestAccount::wallet(Pubkey::new_unique());
+
+        let transfer = CidInstruction::TransferOwnership { new_owner: new_owner.key };
+        assert_eq!(env.send_as(&mut intruder, transfer.clone()), Err(CidError::Unauthorized.into()));
+
+        take_events();
+        env.send(transfer).unwrap();
+        assert_eq!(env.account().owner, new_owner.key);
+        assert_eq!(take_events(), vec![CidEvent::OwnershipTransferred {
+            account: env.cid_account.key,
+            old_owner: env.owner.key,
+            new_owner: new_owner.key,
+        }]);
+
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: cid(1) }), Err(CidError::Unauthorized.into()));
+        env.send_as(&mut new_owner, CidInstruction::StoreCid { cid: cid(1) }).unwrap();
+    }
+
+    #[test]
+    fn transfer_ownership_drops_the_recovery_key() {
+        let mut env = TestEnv::initialized();
+        let mut recovery_key = TestAccount::wallet(Pubkey::new_unique());
+        let new_owner = Pubkey::new_unique();
+
+        env.send(CidInstruction::SetRecoveryKey { recovery_key: Some(recovery_key.key), delay: 3_600 }).unwrap();
+        env.send_as(&mut recovery_key, CidInstruction::RequestRecovery).unwrap();
+        env.send(CidInstruction::TransferOwnership { new_owner }).unwrap();
+
+        let cid_account = env.account();
+        assert_eq!(cid_account.recovery_key, None);
+        assert_eq!(cid_account.recovery_delay, 0);
+        assert_eq!(cid_account.recovery_requested_at, None);
+        assert_eq!(env.send_as(&mut recovery_key, CidInstruction::RequestRecovery), Err(CidError::Unauthorized.into()));
+        assert_eq!(env.send_as(&mut recovery_key, CidInstruction::Recover { new_owner }), Err(CidError::Unauthorized.into()));
+
+        // The account stays at the address derived from the original owner
+        assert_eq!(cid_account.seed_owner, env.owner.key);
+        assert_eq!(cid_account_address(&cid_account.seed_owner), (env.cid_account.key, cid_account.bump));
+        assert_ne!(cid_account_address(&new_owner).0, env.cid_account.key);
+    }
+
+    #[test]
+    fn get_cid_at_returns_the_entry() {
+        let mut env = TestEnv::initialized();
+        assert_eq!(env.send(CidInstruction::GetCidAt { index: 0 }), Err(CidError::IndexOutOfRange.into()));
+
+        env.send(CidInstruction::StoreCids { cids: vec![cid(0), cid(1), cid(2)] }).unwrap();
+        let mut reader = TestAccount::wallet(Pubkey::new_unique());
+        reader.is_signer = false;
+        for index in [0, 2] {
+            env.send_as(&mut reader, CidInstruction::GetCidAt { index }).unwrap();
+            assert_eq!(RETURN_DATA.with(|data| data.borrow().clone()), cid(index as usize).into_bytes());
+        }
+
+        assert_eq!(env.send(CidInstruction::GetCidAt { index: 5 }), Err(CidError::IndexOutOfRange.into()));
+    }
+
+    #[test]
+    fn labels_can_be_looked_up() {
+        let mut env = TestEnv::initialized();
+        env.send(CidInstruction::StoreCidLabeled { cid: cid(1), label: "avatar-v1".to_string() }).unwrap();
+        env.send(CidInstruction::StoreCid { cid: cid(2) }).unwrap();
+
+        let cid_account = env.account();
+        assert_eq!(cid_account.find_by_label("avatar-v1"), Some(&cid(1)));
+        assert_eq!(cid_account.find_by_label("avatar-v2"), None);
+        assert_eq!(cid_account.find_by_label(""), None);
+
+        let label = "x".repeat(MAX_LABEL_LEN + 1);
+        assert_eq!(env.send(CidInstruction::StoreCidLabeled { cid: cid(3), label }), Err(CidError::LabelTooLong.into()));
+    }
+
+    #[test]
+    fn clear_history_keeps_the_account() {
+        let mut env = TestEnv::initialized();
+        env.send(CidInstruction::StoreCids { cids: vec![cid(1), cid(2)] }).unwrap();
+        take_events();
+
+        env.send(CidInstruction::ClearHistory).unwrap();
+        let cid_account = env.account();
+        assert_eq!(cid_account.cid_count, 0);
+        assert_eq!(cid_account.get_cid_at(0), None);
+        assert_eq!(cid_account.latest_cid, "");
+        assert_eq!(take_events(), vec![CidEvent::HistoryCleared { account: env.cid_account.key, owner: env.owner.key }]);
+
+        env.send(CidInstruction::StoreCid { cid: cid(1) }).unwrap();
+    }
+
+    #[test]
+    fn account_data_round_trips() {
+        let mut cid_account = new_account(Pubkey::new_unique());
+        let data = cid_account.to_account_data();
+        assert_eq!(data[..8], CidAccount::DISCRIMINATOR);
+        assert_eq!(CidAccount::try_from_account_data(&data), Ok(cid_account.clone()));
+
+        cid_account.store_cids(&cid_account.owner.clone(), vec![cid(1), CIDV0.to_string()], &clock_at(2, 5)).unwrap();
+        let mut buffer = vec![0xaa; CidAccount::SPACE];
+        cid_account.pack_into(&mut buffer).unwrap();
+        assert_eq!(CidAccount::try_from_account_data(&buffer), Ok(cid_account));
+
+        assert_eq!(CidAccount::try_from_account_data(&[0; 4]), Err(ProgramError::AccountDataTooSmall));
+        assert_eq!(CidAccount::try_from_account_data(&[0; 64]), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn space_fits_a_full_account() {
+        let owner = Pubkey::new_unique();
+        let mut cid_account = new_account(owner);
+        let longest = |n: usize| format!("b{:a>width$}", cid(n), width = MAX_CID_LEN - 1);
+        for n in 0..MAX_CIDS - 1 {
+            cid_account.store_cid_labeled(&owner, longest(n), "l".repeat(MAX_LABEL_LEN), &clock_at(n as u64, 0)).unwrap();
+        }
+        let metadata = (0..MAX_METADATA_PAIRS)
+            .map(|n| (n.to_string(), "v".repeat(MAX_METADATA_BYTES / MAX_METADATA_PAIRS - 1)))
+            .collect();
+        cid_account.store_cid_with_kv(&owner, longest(MAX_CIDS), metadata, &clock_at(MAX_CIDS as u64, 0)).unwrap();
+        cid_account.labels[MAX_CIDS - 1] = "l".repeat(MAX_LABEL_LEN);
+        cid_account.prev_cid = longest(0);
+        cid_account.claimed_size = Some(u64::MAX);
+        cid_account.recovery_key = Some(owner);
+        cid_account.recovery_requested_at = Some(0);
+        cid_account.org_namespace = Some("n".repeat(MAX_SEED_LEN));
+        cid_account.org_program = Some(owner);
+        cid_account.expiry_slot = Some(0);
+
+        let mut buffer = vec![0; CidAccount::SPACE];
+        cid_account.pack_into(&mut buffer).unwrap();
+        assert_eq!(cid_account.to_account_data().len(), CidAccount::SPACE);
+    }
+
+    #[test]
+    fn cid_account_address_matches_find_program_address() {
+        let owner = Pubkey::new_unique();
+        assert_eq!(cid_account_address(&owner), Pubkey::find_program_address(&[CID_SEED, owner.as_ref()], &ID));
+        assert_ne!(cid_account_address(&owner).0, cid_account_address(&Pubkey::new_unique()).0);
+    }
+
+    #[test]
+    fn store_cid_with_size_instruction_records_the_claim() {
+        let mut env = TestEnv::initialized();
+        let store = |n: usize, claimed_size: u64| CidInstruction::StoreCidWithSize { cid: cid(n), claimed_size };
+        assert_eq!(env.send(store(1, 0)), Err(ProgramError::InvalidArgument));
+
+        env.send(store(1, 2048)).unwrap();
+        assert_eq!(env.account().claimed_size, Some(2048));
+        assert!(env.account().verify_content_length(2048));
+
+        // The claim describes the latest CID only
+        env.send(CidInstruction::StoreCid { cid: cid(2) }).unwrap();
+        assert_eq!(env.account().claimed_size, None);
+    }
+
+    #[test]
+    fn claimed_size_is_recorded_and_verified() {
+        let clock = clock_at(1, 0);
+        let owner = Pubkey::new_unique();
+        let mut cid_account = new_account(owner);
+
+        assert_eq!(cid_account.store_cid_with_size(&owner, cid(1), 0, &clock), Err(ProgramError::InvalidArgument));
+        cid_account.store_cid_with_size(&owner, cid(1), 1024, &clock).unwrap();
+        assert_eq!(cid_account.claimed_size, Some(1024));
+        assert!(cid_account.verify_content_length(1024));
+        // A gateway serving fewer bytes than claimed points at a truncated upload
+        assert!(!cid_account.verify_content_length(1000));
+    }
+
+    #[test]
+    fn removing_the_latest_cid_drops_its_claim() {
+        let mut env = TestEnv::initialized();
+        let kv = vec![("k".to_string(), "v".to_string())];
+        env.send(CidInstruction::StoreCidWithSize { cid: cid(1), claimed_size: 1024 }).unwrap();
+        env.send(CidInstruction::StoreCidWithSize { cid: cid(2), claimed_size: 2048 }).unwrap();
+
+        env.send(CidInstruction::RemoveCid { cid: cid(2) }).unwrap();
+        let cid_account = env.account();
+        assert_eq!(cid_account.latest_cid, cid(1));
+        assert_eq!(cid_account.claimed_size, None);
+        assert!(!cid_account.verify_content_length(2048));
+
+        env.send(CidInstruction::StoreCidWithKv { cid: cid(3), kv: kv.clone() }).unwrap();
+        // Removing an older entry leaves the latest CID's metadata alone
+        env.send(CidInstruction::RemoveCid { cid: cid(1) }).unwrap();
+        assert_eq!(env.account().metadata, kv);
+
+        env.send(CidInstruction::RemoveCid { cid: cid(3) }).unwrap();
+        assert!(env.account().metadata.is_empty());
+    }
+
+    #[test]
+    fn recovery_waits_out_the_time_lock() {
+        let owner = Pubkey::new_unique();
+        let recovery_key = Pubkey::new_unique();
+        let new_owner = Pubkey::new_unique();
+        let mut cid_account = new_account(owner);
+
+        assert_eq!(cid_account.set_recovery_key(&owner, Some(recovery_key), 0), Err(ProgramError::InvalidArgument));
+        assert_eq!(cid_account.set_recovery_key(&recovery_key, Some(recovery_key), MIN_RECOVERY_DELAY), Err(CidError::Unauthorized.into()));
+        cid_account.set_recovery_key(&owner, Some(recovery_key), MIN_RECOVERY_DELAY).unwrap();
+
+        assert_eq!(cid_account.recover(&recovery_key, new_owner, &clock_at(1, 1_000)), Err(CidError::NoPendingRecovery.into()));
+        assert_eq!(cid_account.request_recovery(&new_owner, &clock_at(1, 1_000)), Err(CidError::Unauthorized.into()));
+        cid_account.request_recovery(&recovery_key, &clock_at(1, 1_000)).unwrap();
+
+        let unlocks_at = 1_000 + MIN_RECOVERY_DELAY;
+        assert_eq!(cid_account.recover(&recovery_key, new_owner, &clock_at(2, unlocks_at - 1)), Err(CidError::RecoveryLocked.into()));
+        assert_eq!(cid_account.recover(&new_owner, new_owner, &clock_at(3, unlocks_at)), Err(CidError::Unauthorized.into()));
+        assert_eq!(cid_account.owner, owner);
+
+        cid_account.recover(&recovery_key, new_owner, &clock_at(3, unlocks_at)).unwrap();
+        assert_eq!(cid_account.owner, new_owner);
+        assert_eq!(cid_account.recovery_requested_at, None);
+    }
+
+    #[test]
+    fn recovery_instructions_reassign_a_lost_account() {
+        let mut env = TestEnv::initialized();
+        let mut recovery_key = TestAccount::wallet(Pubkey::new_unique());
+        let mut intruder = TestAccount::wallet(Pubkey::new_unique());
+        let new_owner = Pubkey::new_unique();
+
+        let set_key = CidInstruction::SetRecoveryKey { recovery_key: Some(recovery_key.key), delay: 3_600 };
+        assert_eq!(env.send_as(&mut intruder, set_key.clone()), Err(CidError::Unauthorized.into()));
+        env.send(set_key).unwrap();
+        assert_eq!(env.account().recovery_key, Some(recovery_key.key));
+
+        assert_eq!(env.send_as(&mut intruder, CidInstruction::RequestRecovery), Err(CidError::Unauthorized.into()));
+        take_events();
+        env.send_as(&mut recovery_key, CidInstruction::RequestRecovery).unwrap();
+        assert_eq!(take_events(), vec![CidEvent::RecoveryRequested {
+            account: env.cid_account.key,
+            recovery_key: recovery_key.key,
+            unlocks_at: 1_700_003_600,
+        }]);
+
+        set_clock(2, 1_700_003_599);
+        assert_eq!(env.send_as(&mut recovery_key, CidInstruction::Recover { new_owner }), Err(CidError::RecoveryLocked.into()));
+
+        set_clock(3, 1_700_003_600);
+        assert_eq!(env.send_as(&mut intruder, CidInstruction::Recover { new_owner }), Err(CidError::Unauthorized.into()));
+        take_events();
+        env.send_as(&mut recovery_key, CidInstruction::Recover { new_owner }).unwrap();
+        assert_eq!(env.account().owner, new_owner);
+        assert_eq!(take_events(), vec![CidEvent::OwnershipTransferred {
+            account: env.cid_account.key,
+            old_owner: env.owner.key,
+            new_owner,
+        }]);
+    }
+
+    #[test]
+    fn initialize_with_recovery_sets_the_key_up_front() {
+        let recovery_key = Pubkey::new_unique();
+        let mut env = TestEnv::new();
+        let owner = env.owner.key;
+        let init = |delay: i64| CidInstruction::InitializeWithRecovery { owner, recovery_key, delay };
+
+        let mut accounts = [&mut env.cid_account, &mut env.owner, &mut env.system_program];
+        assert_eq!(run(init(MIN_RECOVERY_DELAY - 1), &mut accounts), Err(ProgramError::InvalidArgument));
+        run(init(MIN_RECOVERY_DELAY), &mut accounts).unwrap();
+        env.cid_account.owner = ID;
+
+        let cid_account = env.account();
+        assert_eq!(cid_account.owner, owner);
+        assert_eq!(cid_account.recovery_key, Some(recovery_key));
+        assert_eq!(cid_account.recovery_delay, MIN_RECOVERY_DELAY);
+        assert_eq!(take_events(), vec![CidEvent::AccountInitialized { account: env.cid_account.key, owner }]);
+    }
+
+    #[test]
+    fn metadata_is_bounded() {
+        let clock = clock_at(1, 0);
+        let owner = Pubkey::new_unique();
+        let mut cid_account = new_account(owner);
+
+        let kv = vec![
+            ("commit".to_string(), "abc123".to_string()),
+            ("pipeline".to_string(), "nightly".to_string()),
+        ];
+        cid_account.store_cid_with_kv(&owner, cid(1), kv.clone(), &clock).unwrap();
+        assert_eq!(cid_account.metadata, kv);
+
+        let too_large = vec![("blob".to_string(), "x".repeat(MAX_METADATA_BYTES))];
+        assert_eq!(cid_account.store_cid_with_kv(&owner, cid(2), too_large, &clock), Err(CidError::MetadataTooLarge.into()));
+        let too_many = (0..=MAX_METADATA_PAIRS).map(|n| (n.to_string(), String::new())).collect();
+        assert_eq!(cid_account.store_cid_with_kv(&owner, cid(2), too_many, &clock), Err(CidError::MetadataTooLarge.into()));
+    }
+
+    #[test]
+    fn store_cid_with_kv_instruction_surfaces_metadata() {
+        let mut env = TestEnv::initialized();
+        let kv = vec![
+            ("commit".to_string(), "abc123".to_string()),
+            ("pipeline".to_string(), "nightly".to_string()),
+        ];
+        take_events();
+        env.send(CidInstruction::StoreCidWithKv { cid: cid(1), kv: kv.clone() }).unwrap();
+
+        assert_eq!(env.account().metadata, kv);
+        assert_eq!(take_events(), vec![
+            CidEvent::CidStored { account: env.cid_account.key, owner: env.owner.key, cid: cid(1), count: 1 },
+            CidEvent::MetadataAttached { account: env.cid_account.key, cid: cid(1), kv },
+        ]);
+
+        let too_large = vec![("blob".to_string(), "x".repeat(MAX_METADATA_BYTES))];
+        assert_eq!(env.send(CidInstruction::StoreCidWithKv { cid: cid(2), kv: too_large }), Err(CidError::MetadataTooLarge.into()));
+    }
+
+    // A bucket env whose owner is the org PDA of `namespace` under a fresh
+    // authority program, plus the instruction that initializes it
+    fn org_env(namespace: &str) -> (TestEnv, CidInstruction) {
+        let authority_program = Pubkey::new_unique();
+        let (org, bump) = Pubkey::find_program_address(&[ORG_SEED, namespace.as_bytes()], &authority_program);
+        let env = TestEnv::for_owner(org);
+        (env, CidInstruction::InitializeOrg { namespace: namespace.to_string(), bump, authority_program })
+    }
+
+    fn initialize_org(env: &mut TestEnv, payer: &mut TestAccount, instruction: CidInstruction) -> ProgramResult {
+        let result = run(instruction, &mut [&mut env.cid_account, &mut env.owner, payer, &mut env.system_program]);
+        if result.is_ok() {
+            env.cid_account.owner = ID;
+        }
+        result
+    }
+
+    #[test]
+    fn org_bucket_accepts_writes_signed_by_the_org_pda() {
+        let (mut env, initialize) = org_env("acme");
+        let CidInstruction::InitializeOrg { bump, authority_program, .. } = initialize else { unreachable!() };
+        let mut payer = TestAccount::wallet(Pubkey::new_unique());
+        initialize_org(&mut env, &mut payer, initialize).unwrap();
+
+        let cid_account = env.account();
+        assert_eq!(cid_account.owner, env.owner.key);
+        assert_eq!(cid_account.org_namespace.as_deref(), Some("acme"));
+        assert_eq!(cid_account.org_program, Some(authority_program));
+        assert_eq!(payer.lamports, 1_000_000_000 - env.cid_account.lamports);
+
+        // The org PDA signs, as its authority program would via invoke_signed
+        env.send(CidInstruction::StoreCidAsOrg { namespace: "acme".to_string(), bump, cid: cid(1) }).unwrap();
+        assert_eq!(env.account().cids, vec![cid(1)]);
+    }
+
+    #[test]
+    fn org_bucket_rejects_other_signers() {
+        let (mut env, initialize) = org_env("acme");
+        let CidInstruction::InitializeOrg { bump, .. } = initialize else { unreachable!() };
+        let mut payer = TestAccount::wallet(Pubkey::new_unique());
+
+        env.owner.is_signer = false;
+        assert_eq!(initialize_org(&mut env, &mut payer, initialize.clone()), Err(ProgramError::MissingRequiredSignature));
+        env.owner.is_signer = true;
+
+        // A wallet cannot stand in for the org PDA
+        let mut impostor = TestEnv::new();
+        assert_eq!(initialize_org(&mut impostor, &mut payer, initialize.clone()), Err(ProgramError::InvalidSeeds));
+
+        initialize_org(&mut env, &mut payer, initialize).unwrap();
+        let mut intruder = TestAccount::wallet(Pubkey::new_unique());
+        let store = |namespace: &str| CidInstruction::StoreCidAsOrg { namespace: namespace.to_string(), bump, cid: cid(1) };
+        assert_eq!(env.send_as(&mut intruder, store("acme")), Err(CidError::Unauthorized.into()));
+        assert_eq!(env.send(store("other")), Err(ProgramError::InvalidSeeds));
+        assert!(env.account().cids.is_empty());
+    }
+
+    #[test]
+    fn min_slot_gap_spaces_out_writes() {
+        let owner = Pubkey::new_unique();
+        let mut cid_account = new_account(owner);
+        cid_account.set_min_slot_gap(&owner, 10).unwrap();
+
+        cid_account.store_cid(&owner, cid(1), &clock_at(100, 0)).unwrap();
+        assert_eq!(cid_account.store_cid(&owner, cid(2), &clock_at(109, 0)), Err(CidError::WriteTooSoon.into()));
+        cid_account.store_cid(&owner, cid(2), &clock_at(110, 0)).unwrap();
+        assert_eq!(cid_account.last_write_slot, Some(110));
+    }
+
+    #[test]
+    fn set_min_slot_gap_instruction_gates_stores_on_the_clock_slot() {
+        let mut env = TestEnv::initialized();
+        let mut intruder = TestAccount::wallet(Pubkey::new_unique());
+        assert_eq!(env.send_as(&mut intruder, CidInstruction::SetMinSlotGap { gap: 5 }), Err(CidError::Unauthorized.into()));
+        env.send(CidInstruction::SetMinSlotGap { gap: 5 }).unwrap();
+
+        set_clock(100, 1_700_000_100);
+        env.send(CidInstruction::StoreCid { cid: cid(1) }).unwrap();
+        set_clock(104, 1_700_000_104);
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: cid(2) }), Err(CidError::WriteTooSoon.into()));
+        set_clock(105, 1_700_000_105);
+        env.send(CidInstruction::StoreCid { cid: cid(2) }).unwrap();
+        assert_eq!(env.account().last_write_slot, Some(105));
+    }
+
+    #[test]
+    fn store_final_seals_the_account() {
+        let clock = clock_at(1, 0);
+        let owner = Pubkey::new_unique();
+        let mut cid_account = new_account(owner);
+
+        cid_account.store_final(&owner, cid(1), &clock).unwrap();
+        assert!(cid_account.sealed);
+        assert_eq!(cid_account.store_cid(&owner, cid(2), &clock), Err(CidError::AccountSealed.into()));
+        assert_eq!(cid_account.remove_cid(&owner, &cid(1)), Err(CidError::AccountSealed.into()));
+        assert_eq!(cid_account.cids, vec![cid(1)]);
+    }
+
+    #[test]
+    fn store_final_instruction_rejects_later_writes() {
+        let mut env = TestEnv::initialized();
+        env.send(CidInstruction::StoreFinal { cid: cid(1) }).unwrap();
+        assert!(env.account().sealed);
+
+        assert_eq!(env.send(CidInstruction::StoreCid { cid: cid(2) }), Err(CidError::AccountSealed.into()));
+        assert_eq!(env.send(CidInstruction::StoreFinal { cid: cid(2) }), Err(CidError::AccountSealed.into()));
+        assert_eq!(env.send(CidInstruction::ClearHistory), Err(CidError::AccountSealed.into()));
+        assert_eq!(env.send(CidInstruction::CloseAccount), Err(CidError::AccountSealed.into()));
+        assert_eq!(env.cid_account.owner, ID);
+        assert_eq!(env.account().cids, vec![cid(1)]);
+    }
+
+    #[test]
+    fn temporary_cid_reverts_at_expiry() {
+        let owner = Pubkey::new_unique();
+        let mut cid_account = new_account(owner);
+        cid_account.store_cid(&owner, cid(1), &clock_at(10, 0)).unwrap();
+
+        assert_eq!(cid_account.store_cid_temporary(&owner, cid(2), 20, &clock_at(20, 0)), Err(ProgramError::InvalidArgument));
+        cid_account.store_cid_temporary(&owner, cid(2), 50, &clock_at(20, 0)).unwrap();
+
+        assert_eq!(cid_account.effective_cid(&clock_at(49, 0)), cid(2));
+        assert_eq!(cid_account.effective_cid(&clock_at(50, 0)), cid(1));
+        assert_eq!(cid_account.latest_cid, cid(2));
+    }
+
+    #[test]
+    fn store_cid_temporary_instruction_reverts_on_read() {
+        let mut env = TestEnv::initialized();
+        env.send(CidInstruction::StoreCid { cid: cid(1) }).unwrap();
+
+        set_clock(10, 1_700_000_010);
+        let temporary = |expiry_slot: u64| CidInstruction::StoreCidTemporary { cid: cid(2), expiry_slot };
+        assert_eq!(env.send(temporary(10)), Err(ProgramError::InvalidArgument));
+        env.send(temporary(30)).unwrap();
+
+        assert_eq!(env.account().expiry_slot, Some(30));
+
+        let mut reader = TestAccount::wallet(Pubkey::new_unique());
+        reader.is_signer = false;
+        for (slot, expected) in [(29, cid(2)), (30, cid(1))] {
+            set_clock(slot, 1_700_000_000 + slot as i64);
+            env.send_as(&mut reader, CidInstruction::GetEffectiveCid).unwrap();
+            assert_eq!(RETURN_DATA.with(|data| data.borrow().clone()), expected.into_bytes());
+        }
+        assert_eq!(env.account().latest_cid, cid(2));
+    }
+
+    fn storage_with(owners: &[Pubkey]) -> (CidStorage, Vec<String>) {
+        let clock = clock_at(1, 0);
+        let mut storage = CidStorage::new();
+        // Arbitrary keys, so that one owner can hold several accounts
+        let keys = owners.iter().map(|owner| {
+            let account_key = Pubkey::new_unique();
+            storage.initialize_unchecked(account_key, *owner, 255, &clock).unwrap();
+            account_key.to_string()
+        }).collect();
+        (storage, keys)
+    }
+
+    #[test]
+    fn accounts_are_grouped_by_owner() {
+        let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (mut storage, keys) = storage_with(&[alice, bob, alice]);
+        storage.store_cid(&keys[2], &alice, cid(1), &clock_at(1, 0)).unwrap();
+
+        let mut alice_keys = [keys[0].clone(), keys[2].clone()];
+        alice_keys.sort();
+        let alice_accounts: Vec<&CidAccount> = alice_keys.iter().map(|key| storage.get_account(key).unwrap()).collect();
+        assert_eq!(storage.accounts_for_owner(&alice), alice_accounts);
+        assert_eq!(storage.accounts_for_owner(&bob), vec![storage.get_account(&keys[1]).unwrap()]);
+
+        storage.transfer_ownership(&keys[0], &alice, bob).unwrap();
+        assert_eq!(storage.accounts_for_owner(&alice).len(), 1);
+        assert_eq!(storage.accounts_for_owner(&bob).len(), 2);
+
+        storage.close_account(&keys[2], &alice).unwrap();
+        assert!(storage.accounts_for_owner(&alice).is_empty());
+    }
+
+    #[test]
+    fn load_migrates_a_v1_blob() {
+        // Pubkeys serialize as their 32 raw bytes
+        let owner = Pubkey::new_from_array([7; 32]);
+        let json = format!(r#"{{"accounts":{{"acct1":{{"owner":[{}],"cid_count":3,"latest_cid":"{}"}}}}}}"#, ["7"; 32].join(","), CIDV0);
+        let mut data = (json.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(json.as_bytes());
+
+        let storage = CidStorage::load(&data).unwrap();
+        let cid_account = storage.get_account("acct1").unwrap();
+        assert_eq!(cid_account.cid_count, 3);
+        assert_eq!(cid_account.cids, vec![CIDV0.to_string()]);
+        assert_eq!(cid_account.timestamps, vec![0]);
+        assert_eq!(cid_account.labels, vec![String::new()]);
+        assert_eq!(cid_account.owner, owner);
+        assert_eq!(cid_account.seed_owner, owner);
+        assert_eq!(storage.accounts_for_owner(&owner).len(), 1);
+
+        let mut saved = vec![0; 4096];
+        storage.save(&mut saved).unwrap();
+        let reloaded = CidStorage::load(&saved).unwrap();
+        assert_eq!(reloaded.get_account("acct1"), Some(cid_account));
+    }
+
+    #[test]
+    fn load_rejects_unknown_versions() {
+        let json = br#"{"version":99,"data":{"accounts":{}}}"#;
+        let mut data = (json.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(json);
+        assert!(CidStorage::load(&data).is_err());
+        assert!(CidStorage::load(&[0; 4]).unwrap().stats().total_accounts == 0);
+    }
+
+    #[test]
+    fn stats_aggregate_across_accounts() {
+        let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (mut storage, keys) = storage_with(&[alice, bob, alice]);
+        let clock = clock_at(1, 0);
+        storage.store_cids(&keys[0], &alice, vec![cid(1), cid(2)], &clock).unwrap();
+        storage.store_cid(&keys[1], &bob, cid(3), &clock).unwrap();
+
+        assert_eq!(storage.stats(), StorageStats { total_accounts: 3, total_cids: 3, distinct_owners: 2 });
+    }
+
+    #[test]
+    fn reconcile_adopts_the_chain_state() {
+        let owner = Pubkey::new_unique();
+        let (mut storage, keys) = storage_with(&[owner, owner]);
+        let clock = clock_at(1, 0);
+        storage.store_cid(&keys[0], &owner, cid(1), &clock).unwrap();
+        storage.store_cid(&keys[1], &owner, cid(1), &clock).unwrap();
+
+        // In sync
+        let in_sync_key: Pubkey = keys[0].parse().unwrap();
+        let in_sync = storage.get_account(&keys[0]).unwrap().clone();
+
+        // Stale locally: the chain removed a CID, so the count went down
+        let stale_key: Pubkey = keys[1].parse().unwrap();
+        let mut stale = storage.get_account(&keys[1]).unwrap().clone();
+        stale.remove_cid(&owner, &cid(1)).unwrap();
+
+        // Missing locally
+        let missing_key = Pubkey::new_unique();
+        let mut missing = new_account(owner);
+        missing.store_cid(&owner, cid(2), &clock).unwrap();
+
+        // Malformed on chain
+        let mut misaligned = missing.clone();
+        misaligned.labels.clear();
+
+        let onchain = [
+            (in_sync_key, in_sync.clone()),
+            (stale_key, stale.clone()),
+            (missing_key, missing.clone()),
+            (Pubkey::new_unique(), misaligned),
+        ];
+        assert_eq!(storage.reconcile(&onchain), 2);
+        assert_eq!(storage.get_account(&keys[0]), Some(&in_sync));
+        assert_eq!(storage.get_account(&keys[1]), Some(&stale));
+        assert_eq!(storage.get_account(&missing_key.to_string()), Some(&missing));
+        assert_eq!(storage.stats().total_accounts, 3);
+        assert_eq!(storage.reconcile(&onchain), 0);
+    }
+
+    #[test]
+    fn history_page_clamps_and_pages() {
+        let owner = Pubkey::new_unique();
+        let (mut storage, keys) = storage_with(&[owner]);
+        let history: Vec<String> = (0..45).map(cid).collect();
+        storage.store_cids(&keys[0], &owner, history.clone(), &clock_at(1, 0)).unwrap();
+
+        assert_eq!(storage.history_page(&keys[0], 0, 10), (history[..10].to_vec(), 45));
+        assert_eq!(storage.history_page(&keys[0], 20, 10), (history[20..30].to_vec(), 45));
+        assert_eq!(storage.history_page(&keys[0], 45, 10), (Vec::new(), 45));
+        assert_eq!(storage.history_page(&keys[0], 0, 1000), (history[..MAX_HISTORY_PAGE].to_vec(), 45));
+        assert_eq!(storage.history_page("missing", 0, 10), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn events_are_logged_as_borsh_variants() {
+        let (account, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let initialized = CidEvent::AccountInitialized { account, owner };
+        let data = borsh::to_vec(&initialized).unwrap();
+        assert_eq!(data[0], 0);
+        assert_eq!(CidEvent::try_from_slice(&data).unwrap(), initialized);
+
+        let stored = CidEvent::CidStored { account, owner, cid: CIDV0.to_string(), count: 1 };
+        let data = borsh::to_vec(&stored).unwrap();
+        assert_eq!(data[0], 1);
+        assert_eq!(CidEvent::try_from_slice(&data).unwrap(), stored);
+    }
+
+    #[test]
+    fn removing_the_fallback_keeps_temporary_reads_consistent() {
+        let owner = Pubkey::new_unique();
+        let (mut storage, keys) = storage_with(&[owner]);
+        storage.store_cids(&keys[0], &owner, vec![cid(0), cid(1)], &clock_at(10, 0)).unwrap();
+        storage.store_cid_temporary(&keys[0], &owner, cid(2), 50, &clock_at(20, 0)).unwrap();
+        assert_eq!(storage.effective_cid(&keys[0], &clock_at(50, 0)), Some(cid(1).as_str()));
+
+        // The CID an expired temporary store falls back to is removed
+        storage.remove_cid(&keys[0], &owner, &cid(1)).unwrap();
+        assert_eq!(storage.effective_cid(&keys[0], &clock_at(49, 0)), Some(cid(2).as_str()));
+        assert_eq!(storage.effective_cid(&keys[0], &clock_at(50, 0)), Some(cid(0).as_str()));
+
+        // Removing an unrelated entry leaves the fallback alone
+        storage.store_cid_temporary(&keys[0], &owner, cid(3), 90, &clock_at(40, 0)).unwrap();
+        storage.remove_cid(&keys[0], &owner, &cid(0)).unwrap();
+        assert_eq!(storage.effective_cid(&keys[0], &clock_at(90, 0)), Some(cid(2).as_str()));
+        assert_eq!(storage.effective_cid("missing", &clock_at(90, 0)), None);
+    }
+
+    #[test]
+    fn storage_initialize_requires_the_owner_pda() {
+        let clock = clock_at(1, 0);
+        let owner = Pubkey::new_unique();
+        let (account_key, bump) = cid_account_address(&owner);
+        let mut storage = CidStorage::new();
+
+        assert_eq!(storage.initialize(Pubkey::new_unique(), owner, bump, &clock), Err(ProgramError::InvalidSeeds));
+        assert_eq!(storage.initialize(account_key, owner, bump.wrapping_sub(1), &clock), Err(ProgramError::InvalidSeeds));
+        assert_eq!(storage.stats().total_accounts, 0);
+
+        storage.initialize(account_key, owner, bump, &clock).unwrap();
+        let cid_account = storage.get_account(&account_key.to_string()).unwrap();
+        assert_eq!(cid_account.bump, bump);
+        assert_eq!(storage.accounts_for_owner(&owner).len(), 1);
+
+        // The escape hatch takes any key, e.g. when importing another store
+        let imported = Pubkey::new_unique();
+        storage.initialize_unchecked(imported, owner, 0, &clock).unwrap();
+        assert_eq!(storage.accounts_for_owner(&owner).len(), 2);
+    }
+
+    #[test]
+    fn storage_initialize_org_requires_the_org_cid_address() {
+        let clock = clock_at(1, 0);
+        let authority_program = Pubkey::new_unique();
+        let (org, bump) = Pubkey::find_program_address(&[ORG_SEED, b"acme"], &authority_program);
+        let (account_key, cid_bump) = cid_account_address(&org);
+        let mut storage = CidStorage::new();
+
+        assert_eq!(storage.initialize_org(Pubkey::new_unique(), "acme", bump, authority_program, &clock), Err(ProgramError::InvalidSeeds));
+        storage.initialize_org(account_key, "acme", bump, authority_program, &clock).unwrap();
+        let cid_account = storage.get_account(&account_key.to_string()).unwrap();
+        assert_eq!(cid_account.owner, org);
+        assert_eq!(cid_account.bump, cid_bump);
+    }
 }